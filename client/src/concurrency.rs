@@ -0,0 +1,144 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Average ACK latency this many times above the best seen means the server is queueing
+const RTT_INFLATION: f64 = 4.0;
+
+/// Throughput falling below this fraction of the previous window counts as congestion
+const THROUGHPUT_DROP: f64 = 0.8;
+
+/// How many workers to run: a fixed count or AIMD-controlled
+#[derive(Clone, Copy, Debug)]
+pub enum Threads {
+    Fixed(usize),
+    Auto,
+}
+
+impl FromStr for Threads {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Threads::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(0) => Err("thread count must be at least 1".into()),
+            Ok(n) => Ok(Threads::Fixed(n)),
+            Err(_) => Err(format!("expected a number or 'auto', got '{}'", s)),
+        }
+    }
+}
+
+/// Measurements gathered since the last adjustment
+#[derive(Default)]
+struct Window {
+    bytes: u64,
+    rtt_total: Duration,
+    samples: u32,
+    nacks: u32,
+    started: Option<Instant>,
+    last_throughput: f64,
+    /// Whether the previous adjustment added a worker; only then is a throughput
+    /// drop blamed on concurrency
+    last_increased: bool,
+    min_rtt: Option<Duration>,
+}
+
+/// Decides how many workers may hold a connection. Workers with an id at or above
+/// the limit disconnect and idle until the limit grows again.
+pub struct Controller {
+    limit: AtomicUsize,
+    max: usize,
+    adaptive: bool,
+    window: Mutex<Window>,
+}
+
+impl Controller {
+    pub fn new(threads: Threads, max: usize) -> Self {
+        let (limit, max, adaptive) = match threads {
+            Threads::Fixed(n) => (n, n, false),
+            Threads::Auto => (2.min(max), max, true),
+        };
+        Controller {
+            limit: AtomicUsize::new(limit),
+            max,
+            adaptive,
+            window: Mutex::new(Window::default()),
+        }
+    }
+
+    /// Number of worker threads to spawn; only `limit()` of them are active at a time
+    pub fn max_workers(&self) -> usize {
+        self.max
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn is_active(&self, worker_id: usize) -> bool {
+        worker_id < self.limit()
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Records an acknowledged chunk and the time from the end of its body to its ACK
+    pub fn record_ack(&self, bytes: u64, rtt: Duration) {
+        let mut w = self.lock();
+        w.started.get_or_insert_with(Instant::now);
+        w.bytes += bytes;
+        w.rtt_total += rtt;
+        w.samples += 1;
+        w.min_rtt = Some(w.min_rtt.map_or(rtt, |m| m.min(rtt)));
+    }
+
+    pub fn record_nack(&self) {
+        self.lock().nacks += 1;
+    }
+
+    /// Additive increase while throughput holds and RTT stays near its floor;
+    /// multiplicative decrease on RTT inflation, NACKs or a throughput drop after growing.
+    /// Returns the new limit if it changed.
+    pub fn adjust(&self) -> Option<usize> {
+        if !self.adaptive {
+            return None;
+        }
+        let mut w = self.lock();
+        let elapsed = w.started.map(|s| s.elapsed()).unwrap_or_default();
+        if w.samples == 0 || elapsed.is_zero() {
+            return None;
+        }
+
+        let throughput = w.bytes as f64 / elapsed.as_secs_f64();
+        let avg_rtt = w.rtt_total / w.samples;
+        let min_rtt = w.min_rtt.unwrap_or(avg_rtt);
+        let congested = w.nacks > 0
+            || avg_rtt.as_secs_f64() > min_rtt.as_secs_f64() * RTT_INFLATION
+            || (w.last_increased && throughput < w.last_throughput * THROUGHPUT_DROP);
+
+        let old = self.limit();
+        let new = if congested {
+            (old / 2).max(1)
+        } else {
+            (old + 1).min(self.max)
+        };
+
+        *w = Window {
+            started: Some(Instant::now()),
+            last_throughput: throughput,
+            last_increased: new > old,
+            min_rtt: w.min_rtt,
+            ..Window::default()
+        };
+        self.limit.store(new, Ordering::Relaxed);
+        (new != old).then_some(new)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,279 @@
+use crate::concurrency::Threads;
+use crate::ratelimit::RateSchedule;
+use sha2::{Digest, Sha256};
+use shared::compression::Compression;
+use shared::{FileInfo, Message, ParaFlowError, VersionInfo, read_message, send_message};
+use std::io;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A configured connection to a ParaFlow server. Cheap to clone; every job opens
+/// its own authenticated connections.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub(crate) address: String,
+    pub(crate) user: String,
+    pub(crate) secret: String,
+    pub(crate) compression: Vec<Compression>,
+    pub(crate) threads: Threads,
+    pub(crate) max_threads: usize,
+    pub(crate) limit_rate: Option<RateSchedule>,
+    pub(crate) retries: u32,
+    pub(crate) window: usize,
+}
+
+/// Builds a `Client`; every setting has the same default as the command-line client
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    host: String,
+    port: u16,
+    client: Client,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder {
+            host: "127.0.0.1".into(),
+            port: 7878,
+            client: Client {
+                address: String::new(),
+                user: "admin".into(),
+                secret: "secret123".into(),
+                compression: Vec::new(),
+                threads: Threads::Fixed(4),
+                max_threads: 16,
+                limit_rate: None,
+                retries: 5,
+                window: 4,
+            },
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// User to log in as
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.client.user = user.into();
+        self
+    }
+
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.client.secret = secret.into();
+        self
+    }
+
+    /// Algorithms to offer at login, most preferred first; empty sends data as is
+    pub fn compression(mut self, offer: Vec<Compression>) -> Self {
+        self.client.compression = offer;
+        self
+    }
+
+    /// Parallel connections per job, or `Threads::Auto` to adapt during the transfer
+    pub fn threads(mut self, threads: Threads) -> Self {
+        self.client.threads = threads;
+        self
+    }
+
+    /// Upper bound on connections when adapting
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.client.max_threads = max_threads.max(1);
+        self
+    }
+
+    /// Caps the transfer rate across all connections of a job
+    pub fn limit_rate(mut self, schedule: Option<RateSchedule>) -> Self {
+        self.client.limit_rate = schedule;
+        self
+    }
+
+    /// How often a chunk or connection may fail before a job gives up
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.client.retries = retries;
+        self
+    }
+
+    /// Chunks an upload connection may send before waiting for the first verdict;
+    /// more hides latency on long links at the cost of buffered data
+    pub fn window(mut self, window: usize) -> Self {
+        self.client.window = window.max(1);
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            address: format!("{}:{}", self.host, self.port),
+            ..self.client
+        }
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Logs in with the configured compression offer
+    pub(crate) fn connect(&self) -> Result<(TcpStream, Compression), ParaFlowError> {
+        connect_and_auth(&self.address, &self.user, &self.secret, &self.compression)
+    }
+
+    /// Connections a job starts; in adaptive mode, how many it may grow to
+    pub(crate) fn max_workers(&self) -> usize {
+        match self.threads {
+            Threads::Fixed(n) => n.max(1),
+            Threads::Auto => self.max_threads,
+        }
+    }
+
+    /// Files stored on the server, from its catalog
+    pub fn list(&self) -> Result<Vec<FileInfo>, ParaFlowError> {
+        match self.request(&Message::ListFiles)? {
+            Message::FileList { files } => Ok(files),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    /// Catalog details of one stored file
+    pub fn stat(&self, file_name: &str) -> Result<FileInfo, ParaFlowError> {
+        let reply = self.request(&Message::StatFile {
+            file_name: file_name.to_string(),
+        })?;
+        match reply {
+            Message::FileStat { info } => Ok(info),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    pub fn delete(&self, file_name: &str) -> Result<(), ParaFlowError> {
+        let reply = self.request(&Message::DeleteFile {
+            file_name: file_name.to_string(),
+        })?;
+        match reply {
+            Message::DeleteAck { .. } => Ok(()),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    /// Archived versions of a stored file, newest first
+    pub fn versions(&self, file_name: &str) -> Result<Vec<VersionInfo>, ParaFlowError> {
+        let reply = self.request(&Message::ListVersions {
+            file_name: file_name.to_string(),
+        })?;
+        match reply {
+            Message::VersionList { versions } => Ok(versions),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    pub fn restore(&self, file_name: &str, version_id: &str) -> Result<(), ParaFlowError> {
+        let reply = self.request(&Message::RestoreVersion {
+            file_name: file_name.to_string(),
+            version_id: version_id.to_string(),
+        })?;
+        match reply {
+            Message::RestoreAck { .. } => Ok(()),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    /// Sends a single request on a fresh authenticated connection and returns the reply
+    fn request(&self, msg: &Message) -> Result<Message, ParaFlowError> {
+        let (mut stream, _) = connect_and_auth(&self.address, &self.user, &self.secret, &[])?;
+        send_message(&mut stream, msg)?;
+        read_message(&mut stream)
+    }
+}
+
+/// Longest a login keeps waiting out `Busy` replies before giving up
+const BUSY_WAIT_LIMIT: Duration = Duration::from_secs(5 * 60);
+
+/// Logs in and returns the stream together with the compression the server chose.
+/// A server with no free connection slot is waited for as long as it asks.
+pub(crate) fn connect_and_auth(
+    address: &str,
+    user: &str,
+    password: &str,
+    compression: &[Compression],
+) -> Result<(TcpStream, Compression), ParaFlowError> {
+    let started = Instant::now();
+    loop {
+        match login(address, user, password, compression) {
+            Err(ParaFlowError::ServerBusy(retry_after_ms))
+                if started.elapsed() < BUSY_WAIT_LIMIT =>
+            {
+                thread::sleep(Duration::from_millis(retry_after_ms));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn login(
+    address: &str,
+    user: &str,
+    password: &str,
+    compression: &[Compression],
+) -> Result<(TcpStream, Compression), ParaFlowError> {
+    let mut stream = TcpStream::connect(address)?;
+
+    // 1. Login Request
+    send_message(
+        &mut stream,
+        &Message::LoginRequest {
+            client_id: user.to_string(),
+            compression: compression.to_vec(),
+        },
+    )?;
+
+    // 2. Get Challenge, unless the server is too busy to take us
+    let salt = match read_message(&mut stream)? {
+        Message::LoginChallenge { salt } => salt,
+        Message::Busy { retry_after_ms } => return Err(ParaFlowError::ServerBusy(retry_after_ms)),
+        Message::ShuttingDown => return Err(reply_error(Message::ShuttingDown)),
+        _ => return Err(ParaFlowError::ProtocolError("Expected Challenge".into())),
+    };
+
+    // 3. Solve Puzzle
+    let combined = format!("{}{}", password, salt);
+    let mut hasher = Sha256::new();
+    hasher.update(combined.as_bytes());
+    let answer = hex::encode(hasher.finalize());
+
+    // 4. Send Answer
+    send_message(&mut stream, &Message::LoginAnswer { hash: answer })?;
+
+    // 5. Check Result
+    match read_message(&mut stream)? {
+        Message::Welcome { compression, .. } => Ok((stream, compression)), // Success!
+        Message::ErrorMessage { text } => Err(ParaFlowError::AuthError(text)),
+        _ => Err(ParaFlowError::ProtocolError(
+            "Unexpected message during auth".into(),
+        )),
+    }
+}
+
+/// Turns an error or unexpected reply from the server into an error
+pub(crate) fn reply_error(reply: Message) -> ParaFlowError {
+    match reply {
+        Message::ErrorMessage { text } => ParaFlowError::ServerError(text),
+        // Like a dropped connection: workers reconnect and retry with backoff
+        Message::ShuttingDown => ParaFlowError::Io(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Server is shutting down",
+        )),
+        other => {
+            ParaFlowError::ProtocolError(format!("Server sent unexpected message: {:?}", other))
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::connection::{Client, connect_and_auth, reply_error};
+use crate::progress::{CancelHandle, NoProgress, Progress};
+use crate::ratelimit::{self, RateLimiter};
+use crate::retry::{Backoff, RetryBudget};
+use sha2::{Digest, Sha256};
+use shared::compression::{self, Compression};
+use shared::{
+    ENCRYPTION_KEY, Message, ParaFlowError, encryption, hash_chunk, read_message, send_message,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A stored file to fetch, where to put it and how to report on it
+pub struct DownloadJob {
+    remote_name: String,
+    path: PathBuf,
+    progress: Arc<dyn Progress>,
+    cancel: CancelHandle,
+}
+
+impl DownloadJob {
+    /// Fetches `remote_name` into `path`. The file appears at `path` only once its
+    /// whole-file hash has been verified; until then it is written beside it.
+    pub fn new(remote_name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        DownloadJob {
+            remote_name: remote_name.into(),
+            path: path.into(),
+            progress: Arc::new(NoProgress),
+            cancel: CancelHandle::new(),
+        }
+    }
+
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Stops the download when `handle` is cancelled
+    pub fn cancel_with(mut self, handle: CancelHandle) -> Self {
+        self.cancel = handle;
+        self
+    }
+}
+
+/// A verified download
+#[derive(Debug)]
+pub struct DownloadedFile {
+    pub path: PathBuf,
+    pub file_hash: String,
+    pub size: u64,
+    pub retries: u64,
+    pub elapsed: Duration,
+}
+
+/// Everything the worker threads of one download share
+struct Fetcher {
+    file_name: String,
+    chunk_size: u64,
+    size: u64,
+    negotiated: Compression,
+    server_addr: String,
+    user: String,
+    secret: String,
+    queue: Mutex<Vec<u64>>,
+    part: File,
+    limiter: Option<RateLimiter>,
+    budget: RetryBudget,
+    progress: Arc<dyn Progress>,
+    cancel: CancelHandle,
+    /// Set by the first worker to hit an error no retry can fix
+    fatal: Mutex<Option<ParaFlowError>>,
+    failed: AtomicBool,
+}
+
+impl Fetcher {
+    fn stopped(&self) -> bool {
+        self.budget.is_aborted()
+            || self.cancel.is_cancelled()
+            || self.failed.load(Ordering::Acquire)
+    }
+
+    fn fail(&self, error: ParaFlowError) {
+        self.fatal.lock().unwrap().get_or_insert(error);
+        self.failed.store(true, Ordering::Release);
+    }
+
+    fn chunk_len(&self, chunk_index: u64) -> u64 {
+        self.size.min((chunk_index + 1) * self.chunk_size) - chunk_index * self.chunk_size
+    }
+}
+
+impl Client {
+    /// Fetches a stored file over parallel connections, verifying each chunk and then
+    /// the whole file against the hash the server reported
+    pub fn download(&self, job: DownloadJob) -> Result<DownloadedFile, ParaFlowError> {
+        let started = Instant::now();
+        let (mut stream, negotiated) = self.connect()?;
+        send_message(
+            &mut stream,
+            &Message::DownloadRequest {
+                file_name: job.remote_name.clone(),
+            },
+        )?;
+        let (size, file_hash, chunk_size) = match read_message(&mut stream)? {
+            Message::DownloadInfo {
+                size,
+                file_hash,
+                chunk_size,
+                ..
+            } => (size, file_hash, chunk_size),
+            other => return Err(reply_error(other)),
+        };
+        drop(stream);
+        if chunk_size == 0 {
+            return Err(ParaFlowError::ProtocolError(
+                "Server chose an invalid chunk size".into(),
+            ));
+        }
+        job.progress
+            .started(&job.remote_name, chunk_size, negotiated);
+        job.progress.length(size);
+
+        let part_path = part_path(&job.path);
+        // Read back for the final hash check
+        let part = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part_path)?;
+        part.set_len(size)?;
+        let total_chunks = size.div_ceil(chunk_size);
+        let fetcher = Arc::new(Fetcher {
+            file_name: job.remote_name,
+            chunk_size,
+            size,
+            negotiated,
+            server_addr: self.address.clone(),
+            user: self.user.clone(),
+            secret: self.secret.clone(),
+            queue: Mutex::new((0..total_chunks).rev().collect()),
+            part,
+            limiter: self.limit_rate.clone().map(RateLimiter::new),
+            budget: RetryBudget::new(self.retries),
+            progress: job.progress,
+            cancel: job.cancel,
+            fatal: Mutex::new(None),
+            failed: AtomicBool::new(false),
+        });
+
+        let workers = self.max_workers().min(total_chunks.max(1) as usize);
+        let handles: Vec<_> = (0..workers)
+            .map(|worker_id| {
+                let f = Arc::clone(&fetcher);
+                thread::spawn(move || {
+                    run_worker(&f, worker_id);
+                    f.progress.worker_status(worker_id, "Done");
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let verified = check_failures(&fetcher).and_then(|()| {
+            let actual = hash_part(&fetcher.part)?;
+            if actual != file_hash {
+                return Err(ParaFlowError::TransferFailed(format!(
+                    "Downloaded file hashes to {} but the server reported {}",
+                    actual, file_hash
+                )));
+            }
+            fetcher.part.sync_all()?;
+            fs::rename(&part_path, &job.path)?;
+            Ok(())
+        });
+        if let Err(e) = verified {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        Ok(DownloadedFile {
+            path: job.path,
+            file_hash,
+            size,
+            retries: fetcher.budget.total_retries(),
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Hidden temporary name beside the destination, so the final rename is atomic
+fn part_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.part", name))
+}
+
+fn run_worker(f: &Fetcher, worker_id: usize) {
+    let mut connection: Option<TcpStream> = None;
+    let mut backoff = Backoff::default();
+
+    while !f.stopped() {
+        let Some(chunk_index) = f.queue.lock().unwrap().pop() else {
+            break;
+        };
+
+        if connection.is_none() {
+            match connect_and_auth(&f.server_addr, &f.user, &f.secret, &[f.negotiated]) {
+                Ok((stream, _)) => {
+                    f.progress.worker_status(worker_id, "Connected");
+                    connection = Some(stream);
+                }
+                Err(e) => {
+                    f.queue.lock().unwrap().push(chunk_index);
+                    if backoff.attempts() >= f.budget.limit() {
+                        f.budget.abort(format!("Could not reach server: {}", e));
+                        break;
+                    }
+                    f.budget.count_retry();
+                    f.progress
+                        .retry(None, &format!("Could not connect: {}", e), true);
+                    f.progress
+                        .worker_status(worker_id, &format!("⚠️ Reconnecting: {}", e));
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                }
+            }
+        }
+        let stream = connection.as_mut().expect("connected above");
+
+        f.progress
+            .worker_status(worker_id, &format!("Downloading Chunk #{}", chunk_index));
+        let sent_at = Instant::now();
+        let failure = match fetch_chunk(stream, f, chunk_index) {
+            Ok(data) => match f.part.write_all_at(&data, chunk_index * f.chunk_size) {
+                Ok(()) => {
+                    f.progress
+                        .chunk_done(chunk_index, data.len() as u64, sent_at.elapsed());
+                    backoff.reset();
+                    continue;
+                }
+                Err(e) => {
+                    f.fail(e.into());
+                    break;
+                }
+            },
+            // The server refused the file; asking again will not help
+            Err(e @ ParaFlowError::ServerError(_)) => {
+                f.fail(e);
+                break;
+            }
+            Err(e) => {
+                connection = None;
+                e.to_string()
+            }
+        };
+
+        let will_retry = f.budget.retry(chunk_index, &failure);
+        if will_retry {
+            f.queue.lock().unwrap().push(chunk_index);
+        }
+        f.progress.retry(Some(chunk_index), &failure, will_retry);
+        f.progress.worker_status(
+            worker_id,
+            &format!("⚠️ Chunk #{}: {}", chunk_index, failure),
+        );
+        thread::sleep(backoff.next_delay());
+    }
+}
+
+/// Requests one chunk and returns its verified, decoded contents
+fn fetch_chunk(
+    stream: &mut TcpStream,
+    f: &Fetcher,
+    chunk_index: u64,
+) -> Result<Vec<u8>, ParaFlowError> {
+    send_message(
+        stream,
+        &Message::FetchChunk {
+            file_name: f.file_name.clone(),
+            chunk_index,
+            chunk_size: f.chunk_size,
+        },
+    )?;
+    let (size, hash, compression) = match read_message(stream)? {
+        Message::ChunkData {
+            chunk_index: sent,
+            size,
+            hash,
+            compression,
+        } if sent == chunk_index => (size, hash, compression),
+        other => return Err(reply_error(other)),
+    };
+    let expected = f.chunk_len(chunk_index);
+    if size as u64 > f.chunk_size + encryption::OVERHEAD as u64 {
+        return Err(ParaFlowError::ProtocolError(format!(
+            "Chunk #{} of {} bytes is too large",
+            chunk_index, size
+        )));
+    }
+
+    let mut encrypted = vec![0u8; size];
+    match &f.limiter {
+        Some(limiter) => {
+            for piece in encrypted.chunks_mut(ratelimit::SLICE_SIZE) {
+                limiter.acquire(piece.len());
+                stream.read_exact(piece)?;
+            }
+        }
+        None => stream.read_exact(&mut encrypted)?,
+    }
+    if hash_chunk(&encrypted) != hash {
+        return Err(ParaFlowError::SecurityError(format!(
+            "Chunk #{} failed its integrity check",
+            chunk_index
+        )));
+    }
+    let data = encryption::decrypt_chunk(&encrypted, &ENCRYPTION_KEY)
+        .and_then(|plain| compression::decompress_chunk(&plain, compression, expected as usize))
+        .map_err(ParaFlowError::EncryptionError)?;
+    if data.len() as u64 != expected {
+        return Err(ParaFlowError::ProtocolError(format!(
+            "Chunk #{} has {} bytes, expected {}",
+            chunk_index,
+            data.len(),
+            expected
+        )));
+    }
+    Ok(data)
+}
+
+/// Turns a cancelled download, a fatal error or chunks that ran out of retries into
+/// an error, reporting each failed chunk
+fn check_failures(f: &Fetcher) -> Result<(), ParaFlowError> {
+    let failures = f.budget.failures();
+    let success = failures.is_empty() && !f.stopped();
+    f.progress.finished(success);
+    if success {
+        return Ok(());
+    }
+    if f.cancel.is_cancelled() {
+        return Err(ParaFlowError::Cancelled);
+    }
+    if let Some(error) = f.fatal.lock().unwrap().take() {
+        return Err(error);
+    }
+    for (chunk_index, reason) in &failures {
+        f.progress.chunk_failed(*chunk_index, reason);
+    }
+    let mut summary = format!(
+        "{} chunks failed after {} retries",
+        failures.len() + f.queue.lock().unwrap().len(),
+        f.budget.total_retries()
+    );
+    if let Some(reason) = f.budget.abort_reason() {
+        summary = format!("{}: {}", reason, summary);
+    }
+    Err(ParaFlowError::TransferFailed(summary))
+}
+
+fn hash_part(part: &File) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = part.read_at(&mut buffer, offset)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,32 @@
+//! Client library for ParaFlow servers: parallel, encrypted, resumable uploads and
+//! downloads, delta sync, verification and catalog requests.
+//!
+//! ```no_run
+//! use client::{Client, UploadJob};
+//!
+//! let client = Client::builder().host("files.internal").secret("secret123").build();
+//! let stored = client.upload(UploadJob::file("report.pdf"))?;
+//! println!("Stored as {} ({})", stored.file_name, stored.file_hash);
+//! # Ok::<(), client::ParaFlowError>(())
+//! ```
+
+mod concurrency;
+mod connection;
+mod download;
+mod progress;
+mod ratelimit;
+mod reader;
+mod retry;
+mod sync;
+mod upload;
+mod verify;
+
+pub use concurrency::Threads;
+pub use connection::{Client, ClientBuilder};
+pub use download::{DownloadJob, DownloadedFile};
+pub use progress::{CancelHandle, NoProgress, Progress};
+pub use ratelimit::RateSchedule;
+pub use shared::compression::Compression;
+pub use shared::{FileInfo, ParaFlowError, VersionInfo};
+pub use upload::{StoredFile, UploadJob};
+pub use verify::Verification;
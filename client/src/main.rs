@@ -1,36 +1,212 @@
-use clap::{Parser, Subcommand};
-use hex;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use sha2::{Digest, Sha256};
-use shared::{ENCRYPTION_KEY, Message, ParaFlowError, encryption, read_message, send_message}; // Consolidated imports
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::net::TcpStream;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+mod output;
+mod paths;
+mod watch;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use client::{Client, Compression, DownloadJob, ParaFlowError, RateSchedule, Threads, UploadJob};
+use output::{OutputFormat, Reporter};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use watch::WatchArgs;
 
 #[derive(Parser)]
 #[command(name = "ParaFlow Client")]
 struct Cli {
+    /// "json" prints one event per line on stdout instead of progress bars
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Where the server is and how to log in, shared by every subcommand
+#[derive(Args)]
+struct ServerArgs {
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(short, long, default_value_t = 7878)]
+    port: u16,
+    /// User to log in as
+    #[arg(long, default_value = "admin")]
+    user: String,
+    #[arg(long, default_value = "secret123")]
+    secret: String,
+}
+
+impl ServerArgs {
+    fn client(&self) -> Client {
+        self.builder().build()
+    }
+
+    fn builder(&self) -> client::ClientBuilder {
+        Client::builder()
+            .host(&self.host)
+            .port(self.port)
+            .user(&self.user)
+            .secret(&self.secret)
+    }
+}
+
+/// Compression the client asks for; the server has the final say
+#[derive(ValueEnum, Clone, Copy)]
+enum CompressArg {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl CompressArg {
+    /// Algorithms to offer in the login request, most preferred first
+    fn offer(self) -> Vec<Compression> {
+        match self {
+            CompressArg::None => Vec::new(),
+            CompressArg::Zstd => vec![Compression::Zstd, Compression::Lz4],
+            CompressArg::Lz4 => vec![Compression::Lz4, Compression::Zstd],
+        }
+    }
+}
+
+/// How transfers are carried out, shared by every command that moves file contents
+#[derive(Args)]
+struct TransferArgs {
+    /// Number of parallel connections, or "auto" to adapt it during the transfer
+    #[arg(short, long, default_value = "4")]
+    threads: Threads,
+    /// Upper bound on connections when --threads is auto
+    #[arg(long, default_value_t = 16)]
+    max_threads: usize,
+    /// Compress chunks before encryption when the server supports it
+    #[arg(long, value_enum, default_value_t = CompressArg::None)]
+    compress: CompressArg,
+    /// Cap the transfer rate across all connections, e.g. "2M" or
+    /// "09:00-17:00=512K,unlimited" (K/M/G are binary; windows use local time)
+    #[arg(long)]
+    limit_rate: Option<RateSchedule>,
+    /// How often a chunk or connection may fail before the transfer gives up
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+    /// Chunks each upload connection sends ahead of their acknowledgements
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    window: u16,
+}
+
+impl TransferArgs {
+    fn client(&self, server: &ServerArgs) -> Client {
+        server
+            .builder()
+            .threads(self.threads)
+            .max_threads(self.max_threads)
+            .compression(self.compress.offer())
+            .limit_rate(self.limit_rate.clone())
+            .retries(self.retries)
+            .window(self.window.into())
+            .build()
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Upload {
+        /// Files or quoted "*"/"?" patterns to upload, or "-" to read from stdin
+        #[arg(short, long, num_args = 1.., required = true)]
+        file: Vec<PathBuf>,
+        /// Where to store the upload, like scp: "dir/" (or any destination for several
+        /// files) is a remote directory to put files into, anything else names the
+        /// stored file. Defaults to the local file name; required when reading from stdin
+        #[arg(long, alias = "name")]
+        dest: Option<String>,
+        #[command(flatten)]
+        server: ServerArgs,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Fetch a stored file
+    Download {
+        #[arg(short, long)]
+        name: String,
+        /// Where to write the file; defaults to the last component of the stored name
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+        #[command(flatten)]
+        server: ServerArgs,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Upload new or changed files from a directory as they appear
+    Watch {
+        #[arg(short, long)]
+        dir: PathBuf,
+        #[command(flatten)]
+        server: ServerArgs,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[command(flatten)]
+        watch: WatchArgs,
+    },
+    /// Update a file already on the server by sending only what changed
+    Sync {
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Stored file to update, or "dir/" to put it in a remote directory, as for
+        /// upload; defaults to the local file name
+        #[arg(long)]
+        dest: Option<String>,
+        #[command(flatten)]
+        server: ServerArgs,
+        /// Compress literal data before encryption when the server supports it
+        #[arg(long, value_enum, default_value_t = CompressArg::None)]
+        compress: CompressArg,
+    },
+    /// Check that a stored file still matches a local one
+    Verify {
         #[arg(short, long)]
         file: PathBuf,
-        #[arg(long, default_value = "127.0.0.1")]
-        host: String,
-        #[arg(short, long, default_value_t = 7878)]
-        port: u16,
-        #[arg(short, long, default_value_t = 4)]
-        threads: usize,
-        #[arg(long, default_value = "secret123")]
-        secret: String,
+        /// Stored name to compare against; defaults to the local file name
+        #[arg(long)]
+        name: Option<String>,
+        /// Compare chunks of this many bytes to locate differences; 0 compares
+        /// whole-file hashes only
+        #[arg(long, default_value_t = 4 * 1024 * 1024)]
+        chunk_size: u64,
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// List the files stored on the server
+    List {
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Show catalog details of a stored file
+    Stat {
+        #[arg(short, long)]
+        name: String,
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Delete a stored file
+    Delete {
+        #[arg(short, long)]
+        name: String,
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// List the archived versions of a stored file
+    Versions {
+        #[arg(short, long)]
+        name: String,
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Restore an archived version of a stored file
+    Restore {
+        #[arg(short, long)]
+        name: String,
+        #[arg(long)]
+        version: String,
+        #[command(flatten)]
+        server: ServerArgs,
     },
 }
 
@@ -41,210 +217,251 @@ const BANNER: &str = r#"
 |___|   |___._|__| |___._||___|   |__||_____|________|
 "#;
 
-// UPDATED: Return type is now Result to support the '?' operator
-fn connect_and_auth(address: &str, password: &str) -> Result<TcpStream, ParaFlowError> {
-    let mut stream = TcpStream::connect(address)?;
-
-    // 1. Login Request
-    send_message(
-        &mut stream,
-        &Message::LoginRequest {
-            client_id: "admin".to_string(),
-        },
-    )?;
-
-    // 2. Get Challenge (Now returns Result, so we use ?)
-    if let Message::LoginChallenge { salt } = read_message(&mut stream)? {
-        // 3. Solve Puzzle
-        let combined = format!("{}{}", password, salt);
-        let mut hasher = Sha256::new();
-        hasher.update(combined.as_bytes());
-        let answer = hex::encode(hasher.finalize());
-
-        // 4. Send Answer
-        send_message(&mut stream, &Message::LoginAnswer { hash: answer })?;
-
-        // 5. Check Result
-        match read_message(&mut stream)? {
-            Message::Welcome { .. } => Ok(stream), // Success!
-            Message::ErrorMessage { text } => Err(ParaFlowError::AuthError(text)),
-            _ => Err(ParaFlowError::ProtocolError(
-                "Unexpected message during auth".into(),
-            )),
-        }
-    } else {
-        Err(ParaFlowError::ProtocolError("Expected Challenge".into()))
-    }
-}
-
-fn read_chunk(filename: &str, chunk_index: u64) -> Vec<u8> {
-    let mut file = File::open(filename).expect("File not found");
-    let chunk_size = 4 * 1024 * 1024;
-    file.seek(SeekFrom::Start(chunk_index * chunk_size))
-        .unwrap();
-    let mut buffer = Vec::new();
-    let _ = file.take(chunk_size).read_to_end(&mut buffer);
-    buffer
-}
-
 fn main() {
-    println!("\x1b[36m{}\x1b[0m", BANNER);
     let cli = Cli::parse();
+    output::init(cli.output);
+    output::info(format!("\x1b[36m{}\x1b[0m", BANNER));
 
     match &cli.command {
         Commands::Upload {
             file,
-            host,
-            port,
-            threads,
-            secret,
+            dest,
+            server,
+            transfer,
+        } => {
+            upload(file, dest.as_deref(), &transfer.client(server));
+        }
+        Commands::Download {
+            name,
+            file,
+            server,
+            transfer,
         } => {
-            let filename = file.to_str().expect("Invalid filename");
-            if !file.exists() {
-                eprintln!("Error: File not found");
-                return;
+            let path = file
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(name.rsplit('/').next().unwrap_or(name.as_str())));
+            let job = DownloadJob::new(name, path).progress(Arc::new(Reporter::download()));
+            match transfer.client(server).download(job) {
+                Ok(downloaded) => output::downloaded(&downloaded),
+                Err(e) => output::fail("Download", &e),
             }
-
-            let file_size = std::fs::metadata(file).unwrap().len();
-            let chunk_size = 4 * 1024 * 1024;
-            let total_chunks = (file_size + chunk_size - 1) / chunk_size;
-            let server_addr = format!("{}:{}", host, port);
-
-            let m = MultiProgress::new();
-            let pb_total = m.add(ProgressBar::new(file_size));
-            pb_total.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap().progress_chars("#>-"));
-            pb_total.set_message("Total Progress");
-
-            // --- 1. SETUP PHASE ---
-            let mut current_upload_id = String::new();
-            {
-                // Handle the Result from connect_and_auth
-                let mut setup_stream = match connect_and_auth(&server_addr, secret) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        eprintln!("❌ Connection Failed: {}", e);
-                        std::process::exit(1);
-                    }
-                };
-
-                send_message(
-                    &mut setup_stream,
-                    &Message::InitUpload {
-                        file_name: filename.to_string(),
-                        total_size: file_size,
-                    },
-                )
-                .unwrap();
-
-                match read_message(&mut setup_stream).unwrap() {
-                    Message::InitAck { upload_id, .. } => {
-                        println!("Authorized! Upload ID: {}", upload_id);
-                        current_upload_id = upload_id;
-                    }
-                    Message::ErrorMessage { text } => {
-                        eprintln!("❌ Upload Rejected: {}", text);
-                        std::process::exit(1);
-                    }
-                    _ => panic!("Server sent unexpected message"),
+        }
+        Commands::Watch {
+            dir,
+            server,
+            transfer,
+            watch,
+        } => {
+            if let Err(e) = watch::run(dir, &transfer.client(server), watch) {
+                output::fail("Watch", &e.into());
+            }
+        }
+        Commands::Sync {
+            file,
+            dest,
+            server,
+            compress,
+        } => {
+            let client = server.builder().compression(compress.offer()).build();
+            match client.sync(file, &destination(dest.as_deref(), file, false)) {
+                Ok(stored) => {
+                    let reused = stored.size - stored.bytes_sent;
+                    output::info(format!(
+                        "Reused {} bytes from the server's copy, sent {} changed bytes",
+                        reused, stored.bytes_sent
+                    ));
+                    output::event(
+                        "delta_sent",
+                        json!({ "bytes_reused": reused, "bytes_sent": stored.bytes_sent }),
+                    );
+                    output::stored(&stored);
                 }
+                Err(e) => output::fail("Sync", &e),
             }
-
-            // --- 2. WORKER PHASE ---
-            let upload_id_arc = Arc::new(current_upload_id.clone());
-            let secret_arc = Arc::new(secret.clone());
-            let job_queue = Arc::new(Mutex::new((0..total_chunks).collect::<Vec<u64>>()));
-            let mut handles = vec![];
-
-            for worker_id in 0..*threads {
-                let queue = Arc::clone(&job_queue);
-                let id = Arc::clone(&upload_id_arc);
-                let pass = Arc::clone(&secret_arc);
-                let addr = server_addr.clone();
-                let fname = filename.to_string();
-
-                let pb_worker = m.add(ProgressBar::new_spinner());
-                pb_worker.set_style(
-                    ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {msg}").unwrap(),
+        }
+        Commands::Verify {
+            file,
+            name,
+            chunk_size,
+            server,
+        } => {
+            let remote_name = name.clone().unwrap_or_else(|| base_name(file));
+            let chunk_size = Some(*chunk_size).filter(|&size| size > 0);
+            match server.client().verify(file, &remote_name, chunk_size) {
+                Ok(verification) => output::verified(file, &verification),
+                Err(e) => output::fail("Verify", &e),
+            }
+        }
+        Commands::List { server } => match server.client().list() {
+            Ok(files) if output::is_json() => {
+                output::event("file_list", json!({ "files": files }));
+            }
+            Ok(files) if files.is_empty() => println!("No files stored"),
+            Ok(files) => {
+                println!(
+                    "{:<40} {:>14} {:<12} {:>12}",
+                    "NAME", "SIZE", "UPLOADED BY", "UPLOADED"
+                );
+                for f in files {
+                    println!(
+                        "{:<40} {:>14} {:<12} {:>12}",
+                        f.file_name, f.size, f.uploaded_by, f.uploaded_at
+                    );
+                }
+            }
+            Err(e) => output::fail("Request", &e),
+        },
+        Commands::Stat { name, server } => match server.client().stat(name) {
+            Ok(info) if output::is_json() => {
+                output::event("file_stat", json!({ "file": info }));
+            }
+            Ok(info) => {
+                println!("Name:        {}", info.file_name);
+                println!("Size:        {}", info.size);
+                println!("SHA-256:     {}", info.file_hash);
+                println!("Uploaded by: {}", info.uploaded_by);
+                println!("Uploaded at: {}", info.uploaded_at);
+            }
+            Err(e) => output::fail("Request", &e),
+        },
+        Commands::Delete { name, server } => match server.client().delete(name) {
+            Ok(()) if output::is_json() => {
+                output::event("deleted", json!({ "file_name": name }));
+            }
+            Ok(()) => println!("Deleted {}", name),
+            Err(e) => output::fail("Request", &e),
+        },
+        Commands::Versions { name, server } => match server.client().versions(name) {
+            Ok(versions) if output::is_json() => {
+                output::event(
+                    "version_list",
+                    json!({ "file_name": name, "versions": versions }),
                 );
-                pb_worker.set_prefix(format!("Worker {}", worker_id));
-                let pb_total_clone = pb_total.clone();
-
-                handles.push(thread::spawn(move || {
-                    let mut stream =
-                        connect_and_auth(&addr, &pass).expect("Worker failed to authenticate");
-                    pb_worker.set_message("Connected");
-
-                    loop {
-                        let chunk_index = {
-                            let mut q = queue.lock().unwrap();
-                            match q.pop() {
-                                Some(i) => i,
-                                None => break,
-                            }
-                        };
-
-                        loop {
-                            pb_worker.set_message(format!("Uploading Chunk #{}", chunk_index));
-                            let chunk_data = read_chunk(&fname, chunk_index);
-                            let size_u64 = chunk_data.len() as u64;
-
-                            let encrypted_chunk =
-                                encryption::encrypt_chunk(&chunk_data, &ENCRYPTION_KEY)
-                                    .expect("Encryption failed");
-
-                            let mut hasher = Sha256::new();
-                            hasher.update(&encrypted_chunk);
-                            let hash = hex::encode(hasher.finalize());
-
-                            send_message(
-                                &mut stream,
-                                &Message::ChunkMeta {
-                                    upload_id: id.to_string(),
-                                    chunk_index,
-                                    size: encrypted_chunk.len(),
-                                    hash,
-                                },
-                            )
-                            .unwrap();
-
-                            stream.write_all(&encrypted_chunk).unwrap();
-
-                            match read_message(&mut stream).unwrap() {
-                                Message::ChunkAck { .. } => {
-                                    pb_total_clone.inc(size_u64);
-                                    break;
-                                }
-                                Message::ChunkNack { .. } => {
-                                    pb_worker
-                                        .set_message(format!("⚠️ Chunk #{} Retry...", chunk_index));
-                                    thread::sleep(Duration::from_millis(500));
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    pb_worker.finish_with_message("Done");
-                }));
             }
-            for h in handles {
-                h.join().unwrap();
+            Ok(versions) if versions.is_empty() => {
+                println!("No archived versions of {}", name);
+            }
+            Ok(versions) => {
+                println!("{:<22} {:>14} {:>12}", "VERSION", "SIZE", "MODIFIED");
+                for v in versions {
+                    println!("{:<22} {:>14} {:>12}", v.version_id, v.size, v.modified_at);
+                }
             }
-            pb_total.finish_with_message("Upload Complete!");
-
-            // --- 3. COMPLETE PHASE ---
-            let mut stream =
-                connect_and_auth(&server_addr, secret).expect("Final completion failed");
-            send_message(
-                &mut stream,
-                &Message::Complete {
-                    upload_id: current_upload_id,
-                    file_name: filename.to_string(),
-                    total_chunks,
-                },
-            )
-            .unwrap();
-            println!("Done.");
+            Err(e) => output::fail("Request", &e),
+        },
+        Commands::Restore {
+            name,
+            version,
+            server,
+        } => match server.client().restore(name, version) {
+            Ok(()) if output::is_json() => output::event(
+                "restored",
+                json!({ "file_name": name, "version_id": version }),
+            ),
+            Ok(()) => println!("Restored {} to version {}", name, version),
+            Err(e) => output::fail("Request", &e),
+        },
+    }
+}
+
+fn upload(files: &[PathBuf], dest: Option<&str>, client: &Client) {
+    let invalid = |message: &str| {
+        output::fail(
+            "Upload",
+            &ParaFlowError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            )),
+        )
+    };
+    let from_stdin = files.iter().any(|f| f.as_os_str() == "-");
+    if from_stdin && files.len() > 1 {
+        invalid("stdin cannot be uploaded together with other files");
+    }
+    if from_stdin && dest.is_none_or(|d| d.ends_with('/')) {
+        invalid("--dest must name the stored file when uploading from stdin");
+    }
+    let paths = if from_stdin {
+        files.to_vec()
+    } else {
+        paths::expand(files).unwrap_or_else(|e| output::fail("Upload", &e.into()))
+    };
+
+    if let [file] = paths.as_slice() {
+        if !from_stdin && !file.exists() {
+            output::fail(
+                "Upload",
+                &ParaFlowError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "File not found",
+                )),
+            );
+        }
+        let job = if from_stdin {
+            let name = dest.unwrap_or_default().trim_start_matches('/');
+            UploadJob::reader(std::io::stdin(), name)
+        } else {
+            UploadJob::file(file).remote_name(destination(dest, file, false))
+        };
+        let job = job.progress(Arc::new(Reporter::upload()));
+        match client.upload(job) {
+            Ok(stored) => output::stored(&stored),
+            Err(e) => output::fail("Upload", &e),
+        }
+        return;
+    }
+
+    // Files from different directories may share a base name; storing both under it
+    // would silently replace one with the other, so refuse before sending anything
+    let remote_names: Vec<String> = paths.iter().map(|p| destination(dest, p, true)).collect();
+    let mut claimed = HashMap::new();
+    for (path, remote_name) in paths.iter().zip(&remote_names) {
+        if let Some(other) = claimed.insert(remote_name, path) {
+            invalid(&format!(
+                "{} and {} would both be stored as {}",
+                other.display(),
+                path.display(),
+                remote_name
+            ));
+        }
+    }
+
+    // Every file shares the connections and one total bar
+    let reporter = Reporter::upload();
+    let names: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    let jobs = paths
+        .iter()
+        .zip(&names)
+        .zip(remote_names)
+        .map(|((path, name), remote_name)| {
+            UploadJob::file(path)
+                .remote_name(remote_name)
+                .progress(Arc::new(reporter.for_file(name)))
+        })
+        .collect();
+    let results: Vec<_> = names.into_iter().zip(client.upload_batch(jobs)).collect();
+    reporter.finish(results.iter().all(|(_, r)| r.is_ok()));
+    output::batch(&results);
+}
+
+/// Resolves `--dest` for one local file the way scp does: a trailing `/`, or a
+/// batch of files, makes it a directory to put the file into; otherwise it is the
+/// stored name. Without a destination the file keeps its own name.
+fn destination(dest: Option<&str>, path: &Path, into_dir: bool) -> String {
+    let name = base_name(path);
+    match dest.map(|d| d.trim_start_matches('/')) {
+        None | Some("") => name,
+        Some(dir) if into_dir || dir.ends_with('/') => {
+            format!("{}/{}", dir.trim_end_matches('/'), name)
         }
+        Some(file) => file.to_string(),
     }
 }
+
+/// The last component of a local path, which is what gets stored by default
+fn base_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,460 @@
+use clap::ValueEnum;
+use client::{Compression, DownloadedFile, ParaFlowError, Progress, StoredFile, Verification};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// How the client reports progress and results
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Progress bars and human-readable messages
+    Text,
+    /// One JSON event per line on stdout, for scripts and CI logs
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Selects the output format for the rest of the process
+pub fn init(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+/// Shows a transfer as progress bars in text mode and as events in JSON mode.
+/// Reporters made with `for_file` share the bars of the one they came from, so
+/// the files of a batch fill a single total.
+pub struct Reporter {
+    bars: Arc<Bars>,
+    /// Set for one file of a batch; its events carry the name
+    file: Option<String>,
+    /// Length last reported for this file, so batch totals add up
+    length: AtomicU64,
+    downloading: bool,
+}
+
+struct Bars {
+    bars: MultiProgress,
+    total: ProgressBar,
+    workers: Mutex<HashMap<usize, ProgressBar>>,
+    /// Every file of a batch hears about the same change; print it once
+    connections: AtomicUsize,
+}
+
+impl Reporter {
+    pub fn upload() -> Self {
+        Self::new(false)
+    }
+
+    pub fn download() -> Self {
+        Self::new(true)
+    }
+
+    fn new(downloading: bool) -> Self {
+        // Progress bars draw to the terminal in text mode and nowhere in JSON mode
+        let bars = if is_json() {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        };
+        let total = bars.add(ProgressBar::new(0));
+        total.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .unwrap().progress_chars("#>-"));
+        Reporter {
+            bars: Arc::new(Bars {
+                bars,
+                total,
+                workers: Mutex::new(HashMap::new()),
+                connections: AtomicUsize::new(0),
+            }),
+            file: None,
+            length: AtomicU64::new(0),
+            downloading,
+        }
+    }
+
+    /// A reporter for one file of a batch, drawing into the same bars. It leaves
+    /// the total bar running; `finish` ends it once every file is done.
+    pub fn for_file(&self, file: &str) -> Self {
+        Reporter {
+            bars: Arc::clone(&self.bars),
+            file: Some(file.to_string()),
+            length: AtomicU64::new(0),
+            downloading: self.downloading,
+        }
+    }
+
+    pub fn finish(&self, success: bool) {
+        if success {
+            self.bars.total.finish_with_message("Complete!");
+        } else {
+            self.bars.total.abandon_with_message("Failed");
+        }
+    }
+
+    /// Prints a status line without tearing the bars
+    fn line(&self, line: String) {
+        if !is_json() {
+            let line = match &self.file {
+                Some(file) => format!("{}: {}", file, line),
+                None => line,
+            };
+            self.bars.bars.suspend(|| println!("{}", line));
+        }
+    }
+
+    fn event(&self, kind: &str, mut fields: Value) {
+        if let (Some(file), Value::Object(fields)) = (&self.file, &mut fields) {
+            fields.insert("file".into(), json!(file));
+        }
+        event(kind, fields);
+    }
+}
+
+impl Progress for Reporter {
+    fn started(&self, id: &str, chunk_size: u64, compression: Compression) {
+        if compression != Compression::None {
+            self.line(format!("Compression: {:?}", compression));
+        }
+        let id_field = if self.downloading {
+            self.line(format!("Downloading {}", id));
+            "file_name"
+        } else {
+            self.line(format!("Authorized! Upload ID: {}", id));
+            "upload_id"
+        };
+        self.event(
+            "connected",
+            json!({ id_field: id, "chunk_size": chunk_size, "compression": compression }),
+        );
+    }
+
+    fn length(&self, bytes: u64) {
+        let previous = self.length.swap(bytes, Ordering::Relaxed);
+        self.bars.total.inc_length(bytes.saturating_sub(previous));
+    }
+
+    fn deduplicated(&self, known_chunks: u64, total_chunks: u64, bytes: u64) {
+        self.line(format!(
+            "Skipping {} chunks already on the server",
+            known_chunks
+        ));
+        self.bars.total.inc(bytes);
+        self.event(
+            "deduplicated",
+            json!({ "known_chunks": known_chunks, "total_chunks": total_chunks }),
+        );
+    }
+
+    fn worker_status(&self, worker_id: usize, status: &str) {
+        let mut workers = self.bars.workers.lock().unwrap();
+        let bar = workers.entry(worker_id).or_insert_with(|| {
+            let bar = self.bars.bars.add(ProgressBar::new_spinner());
+            bar.set_style(
+                ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {msg}").unwrap(),
+            );
+            bar.set_prefix(format!("Worker {}", worker_id));
+            bar
+        });
+        if status == "Done" {
+            bar.finish_with_message("Done");
+        } else {
+            bar.set_message(status.to_string());
+        }
+    }
+
+    fn chunk_done(&self, chunk_index: u64, bytes: u64, rtt: Duration) {
+        self.bars.total.inc(bytes);
+        self.event(
+            "chunk_acked",
+            json!({ "chunk_index": chunk_index, "bytes": bytes, "rtt_ms": rtt.as_millis() as u64 }),
+        );
+    }
+
+    fn retry(&self, chunk_index: Option<u64>, reason: &str, will_retry: bool) {
+        self.event(
+            "retry",
+            json!({ "chunk_index": chunk_index, "reason": reason, "will_retry": will_retry }),
+        );
+    }
+
+    fn concurrency(&self, connections: usize) {
+        if self.bars.connections.swap(connections, Ordering::Relaxed) == connections {
+            return;
+        }
+        self.bars.bars.suspend(|| {
+            info(format!(
+                "Concurrency adjusted to {} connections",
+                connections
+            ))
+        });
+        event("concurrency", json!({ "connections": connections }));
+    }
+
+    fn chunk_failed(&self, chunk_index: u64, reason: &str) {
+        match &self.file {
+            Some(file) => warn(format!("   {} chunk #{}: {}", file, chunk_index, reason)),
+            None => warn(format!("   Chunk #{}: {}", chunk_index, reason)),
+        }
+        self.event(
+            "chunk_failed",
+            json!({ "chunk_index": chunk_index, "reason": reason }),
+        );
+    }
+
+    fn finished(&self, success: bool) {
+        if self.file.is_none() {
+            self.finish(success);
+        }
+    }
+}
+
+/// Prints a status line in text mode; JSON mode reports the same through events
+pub fn info(line: impl AsRef<str>) {
+    if !is_json() {
+        println!("{}", line.as_ref());
+    }
+}
+
+/// Prints a problem to stderr in text mode
+pub fn warn(line: impl AsRef<str>) {
+    if !is_json() {
+        eprintln!("{}", line.as_ref());
+    }
+}
+
+/// Emits a JSON event of the given kind with `fields` merged in. Each event is a
+/// single `println!`, so lines from different worker threads never interleave.
+pub fn event(kind: &str, fields: Value) {
+    if !is_json() {
+        return;
+    }
+    let mut line = json!({ "event": kind });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    println!("{}", line);
+}
+
+/// Exit status for each kind of failure, so scripts can tell them apart
+pub fn exit_code(error: &ParaFlowError) -> i32 {
+    match error {
+        ParaFlowError::Io(_) => 3,
+        ParaFlowError::Serialization(_) => 4,
+        ParaFlowError::AuthError(_) => 5,
+        ParaFlowError::ProtocolError(_) => 6,
+        ParaFlowError::SecurityError(_) => 7,
+        ParaFlowError::EncryptionError(_) => 8,
+        ParaFlowError::ServerError(_) => 9,
+        ParaFlowError::TransferFailed(_) => 10,
+        ParaFlowError::Cancelled => 11,
+        ParaFlowError::ServerBusy(_) => 12,
+    }
+}
+
+/// Whether asking again cannot help: the server or the credentials refused the
+/// request, or the local key cannot encrypt it
+pub fn is_permanent(error: &ParaFlowError) -> bool {
+    matches!(
+        error,
+        ParaFlowError::AuthError(_)
+            | ParaFlowError::SecurityError(_)
+            | ParaFlowError::EncryptionError(_)
+            | ParaFlowError::ServerError(_)
+    )
+}
+
+fn error_kind(error: &ParaFlowError) -> &'static str {
+    match error {
+        ParaFlowError::Io(_) => "io",
+        ParaFlowError::Serialization(_) => "serialization",
+        ParaFlowError::AuthError(_) => "auth",
+        ParaFlowError::ProtocolError(_) => "protocol",
+        ParaFlowError::SecurityError(_) => "security",
+        ParaFlowError::EncryptionError(_) => "encryption",
+        ParaFlowError::ServerError(_) => "server",
+        ParaFlowError::TransferFailed(_) => "transfer",
+        ParaFlowError::Cancelled => "cancelled",
+        ParaFlowError::ServerBusy(_) => "busy",
+    }
+}
+
+/// Reports `error` as the outcome of `action` and exits with its exit code
+pub fn fail(action: &str, error: &ParaFlowError) -> ! {
+    let code = exit_code(error);
+    if is_json() {
+        event(
+            "failed",
+            json!({
+                "action": action,
+                "kind": error_kind(error),
+                "error": error.to_string(),
+                "exit_code": code,
+            }),
+        );
+    } else {
+        eprintln!("❌ {} Failed: {}", action, error);
+    }
+    std::process::exit(code);
+}
+
+/// Reports a successful upload: the stored name and verified hash in text mode,
+/// `completed` and `summary` events in JSON mode
+pub fn stored(file: &StoredFile) {
+    if !is_json() {
+        if file.retries > 0 {
+            println!("Recovered after {} retries", file.retries);
+        }
+        println!("Done. Stored as {}", file.file_name);
+        println!("Verified SHA-256: {}", file.file_hash);
+        return;
+    }
+    let secs = file.elapsed.as_secs_f64();
+    let throughput = if secs > 0.0 {
+        file.bytes_sent as f64 / secs
+    } else {
+        0.0
+    };
+    event(
+        "completed",
+        json!({ "file_name": file.file_name, "file_hash": file.file_hash }),
+    );
+    event(
+        "summary",
+        json!({
+            "file_name": file.file_name,
+            "size": file.size,
+            "bytes_sent": file.bytes_sent,
+            "elapsed_secs": secs,
+            "throughput_bytes_per_sec": throughput.round() as u64,
+            "retries": file.retries,
+            "file_hash": file.file_hash,
+        }),
+    );
+}
+
+/// Reports a successful download, like `stored` does for uploads
+pub fn downloaded(file: &DownloadedFile) {
+    if !is_json() {
+        if file.retries > 0 {
+            println!("Recovered after {} retries", file.retries);
+        }
+        println!("Done. Saved to {}", file.path.display());
+        println!("Verified SHA-256: {}", file.file_hash);
+        return;
+    }
+    let secs = file.elapsed.as_secs_f64();
+    let throughput = if secs > 0.0 {
+        file.size as f64 / secs
+    } else {
+        0.0
+    };
+    event(
+        "completed",
+        json!({ "path": file.path, "file_hash": file.file_hash }),
+    );
+    event(
+        "summary",
+        json!({
+            "path": file.path,
+            "size": file.size,
+            "elapsed_secs": secs,
+            "throughput_bytes_per_sec": throughput.round() as u64,
+            "retries": file.retries,
+            "file_hash": file.file_hash,
+        }),
+    );
+}
+
+/// Reports the outcome of every file in a batch and exits with the code of the
+/// first failure, if any
+pub fn batch(results: &[(String, Result<StoredFile, ParaFlowError>)]) {
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    for (file, result) in results {
+        match result {
+            Ok(stored) if is_json() => self::stored(stored),
+            Ok(stored) => println!("✅ {} -> {} ({})", file, stored.file_name, stored.file_hash),
+            Err(e) if is_json() => event(
+                "file_failed",
+                json!({
+                    "file": file,
+                    "kind": error_kind(e),
+                    "error": e.to_string(),
+                    "exit_code": exit_code(e),
+                }),
+            ),
+            Err(e) => eprintln!("❌ {}: {}", file, e),
+        }
+    }
+    info(format!(
+        "{} of {} files uploaded",
+        results.len() - failed,
+        results.len()
+    ));
+    event(
+        "batch_summary",
+        json!({ "files": results.len(), "succeeded": results.len() - failed, "failed": failed }),
+    );
+    if let Some((_, Err(e))) = results.iter().find(|(_, r)| r.is_err()) {
+        std::process::exit(exit_code(e));
+    }
+}
+
+/// Reports how a local file compares with the server's copy; exits with 1 when
+/// they differ, like `cmp`
+pub fn verified(path: &Path, v: &Verification) {
+    if is_json() {
+        let ranges: Vec<Value> = v
+            .differing
+            .iter()
+            .map(|r| json!({ "offset": r.start, "len": r.end - r.start }))
+            .collect();
+        event(
+            "verified",
+            json!({
+                "path": path,
+                "file_name": v.file_name,
+                "match": v.matches(),
+                "local_size": v.local_size,
+                "remote_size": v.remote_size,
+                "local_hash": v.local_hash,
+                "remote_hash": v.remote_hash,
+                "chunk_size": v.chunk_size,
+                "differing_ranges": ranges,
+            }),
+        );
+    } else if v.matches() {
+        println!(
+            "✅ {} matches {} on the server",
+            path.display(),
+            v.file_name
+        );
+        println!("SHA-256: {}", v.local_hash);
+    } else {
+        println!(
+            "❌ {} differs from {} on the server",
+            path.display(),
+            v.file_name
+        );
+        println!("Local:  {} bytes, SHA-256 {}", v.local_size, v.local_hash);
+        println!("Remote: {} bytes, SHA-256 {}", v.remote_size, v.remote_hash);
+        for range in &v.differing {
+            println!(
+                "   bytes {}..{} differ ({} bytes)",
+                range.start,
+                range.end,
+                range.end - range.start
+            );
+        }
+    }
+    if !v.matches() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Expands `*` and `?` in each path, one component at a time, so quoted patterns
+/// work without a shell. Paths without wildcards are passed through as given;
+/// a pattern that matches no regular file is an error.
+pub fn expand(patterns: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !has_wildcard(&pattern.to_string_lossy()) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let mut matches = expand_one(pattern)?;
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No files match {}", pattern.display()),
+            ));
+        }
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+fn expand_one(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let components: Vec<Component> = pattern.components().collect();
+    let mut candidates = vec![PathBuf::new()];
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        let part = component.as_os_str().to_string_lossy();
+        if !has_wildcard(&part) {
+            for candidate in &mut candidates {
+                candidate.push(component);
+            }
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for dir in &candidates {
+            let listing = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            // Directories that vanish or cannot be read simply match nothing
+            let Ok(entries) = fs::read_dir(listing) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Hidden entries only match a pattern that asks for them, like a shell
+                if name.starts_with('.') && !part.starts_with('.') {
+                    continue;
+                }
+                if !matches(&part, &name) {
+                    continue;
+                }
+                let path = dir.join(&name);
+                let keep = if last { path.is_file() } else { path.is_dir() };
+                if keep {
+                    next.push(path);
+                }
+            }
+        }
+        candidates = next;
+    }
+    Ok(candidates.into_iter().filter(|p| p.is_file()).collect())
+}
+
+/// Matches `name` against a pattern where `*` is any run of characters and `?`
+/// is exactly one
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,60 @@
+use shared::compression::Compression;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Receives updates while a transfer runs. Every method has an empty default, so
+/// implementors only override what they display. Calls come from worker threads.
+pub trait Progress: Send + Sync {
+    /// The server accepted the transfer; `id` is the upload id, or the file name
+    /// for downloads
+    fn started(&self, _id: &str, _chunk_size: u64, _compression: Compression) {}
+
+    /// The transfer is now `bytes` long; streams grow as their input is read
+    fn length(&self, _bytes: u64) {}
+
+    /// `known_chunks` of `total_chunks`, `bytes` in all, were already on the server
+    fn deduplicated(&self, _known_chunks: u64, _total_chunks: u64, _bytes: u64) {}
+
+    /// What one worker connection is doing, for display
+    fn worker_status(&self, _worker_id: usize, _status: &str) {}
+
+    /// A chunk made it across; `rtt` is the time spent waiting on the server
+    fn chunk_done(&self, _chunk_index: u64, _bytes: u64, _rtt: Duration) {}
+
+    /// A chunk or connection failed; `chunk_index` is `None` for failed reconnects
+    fn retry(&self, _chunk_index: Option<u64>, _reason: &str, _will_retry: bool) {}
+
+    /// Adaptive concurrency settled on a new number of connections
+    fn concurrency(&self, _connections: usize) {}
+
+    /// A chunk ran out of retries; reported once the workers have stopped
+    fn chunk_failed(&self, _chunk_index: u64, _reason: &str) {}
+
+    /// All workers have stopped, successfully or not
+    fn finished(&self, _success: bool) {}
+}
+
+/// Reports nothing; the default for jobs
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Stops a running job from another thread. Workers finish the chunk in flight,
+/// then the job returns `ParaFlowError::Cancelled`.
+#[derive(Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{Local, Timelike};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bodies are written in slices of this size so a limited upload stays smooth
+pub const SLICE_SIZE: usize = 64 * 1024;
+
+/// A rate in bytes per second; `None` means unlimited
+type Rate = Option<u64>;
+
+/// A `HH:MM-HH:MM` window of the local day; windows may wrap past midnight
+#[derive(Debug, Clone)]
+struct Window {
+    start: u32,
+    end: u32,
+    rate: Rate,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parsed `--limit-rate` value: comma-separated rules, each either a plain rate
+/// (the default) or `HH:MM-HH:MM=RATE`. The first matching window wins.
+#[derive(Debug, Clone)]
+pub struct RateSchedule {
+    default: Rate,
+    windows: Vec<Window>,
+}
+
+impl RateSchedule {
+    /// Rate in force at the current local time
+    pub fn current(&self) -> Rate {
+        let now = Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        self.windows
+            .iter()
+            .find(|w| w.contains(minute))
+            .map_or(self.default, |w| w.rate)
+    }
+}
+
+impl FromStr for RateSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut schedule = RateSchedule {
+            default: None,
+            windows: Vec::new(),
+        };
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            match rule.split_once('=') {
+                Some((span, rate)) => {
+                    let (start, end) = span
+                        .split_once('-')
+                        .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", span))?;
+                    schedule.windows.push(Window {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        rate: parse_rate(rate)?,
+                    });
+                }
+                None => schedule.default = parse_rate(rule)?,
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+/// Parses `512K`, `10M`, `1.5G`, `2MB` (binary multiples) or `unlimited`
+pub fn parse_rate(s: &str) -> Result<Rate, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    let digits = s.trim_end_matches(['B', 'b']);
+    let (number, multiplier) = match digits.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&digits[..digits.len() - 1], 1024.0),
+        Some('M') => (&digits[..digits.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&digits[..digits.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (digits, 1.0),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate '{}'", s))?;
+    let bytes = (value * multiplier) as u64;
+    if bytes == 0 {
+        return Err(format!("rate '{}' must be positive (or 'unlimited')", s));
+    }
+    Ok(Some(bytes))
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let (h, m) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("expected HH:MM, got '{}'", s))?;
+    match (h.parse::<u32>(), m.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => Err(format!("invalid time '{}'", s)),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket shared by all workers, refilled at the schedule's current rate
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> Self {
+        RateLimiter {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Blocks until `bytes` may be sent
+    pub fn acquire(&self, bytes: usize) {
+        loop {
+            let Some(rate) = self.schedule.current() else {
+                return;
+            };
+            let rate = rate as f64;
+            // Allow a quarter second of burst, but always at least one slice
+            let capacity = (rate / 4.0).max(SLICE_SIZE as f64);
+
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.last).as_secs_f64() * rate;
+                bucket.tokens = (bucket.tokens + refill).min(capacity);
+                bucket.last = now;
+                if bucket.tokens >= bytes as f64 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                (bytes as f64 - bucket.tokens) / rate
+            };
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A file opened once and read at chunk offsets with `pread`, so any number of
+/// threads can share the handle without seeking
+pub struct FileReader {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(FileReader {
+            file: File::open(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Replaces the contents of `buffer` with chunk `chunk_index`, which is shorter
+    /// than `chunk_size` only at the end of the file
+    pub fn read_chunk(
+        &self,
+        chunk_index: u64,
+        chunk_size: u64,
+        buffer: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        buffer.clear();
+        buffer.resize(chunk_size as usize, 0);
+        let offset = chunk_index * chunk_size;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let at = offset + filled as u64;
+            match self.file.read_at(&mut buffer[filled..], at) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("Reading {}: {}", self.path.display(), e),
+                    ));
+                }
+            }
+        }
+        buffer.truncate(filled);
+        Ok(())
+    }
+}
+
+/// Chunk-sized buffers handed back after use, so reading a chunk does not
+/// allocate once the pipeline is warm
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    /// Buffers kept for reuse; more are dropped on return
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn give(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Delay before the first retry; doubled on every further failure
+const BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest a worker waits between two attempts
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Exponential backoff with jitter, so workers that failed together do not
+/// hammer the server together
+#[derive(Default)]
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Failures since the last success
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Counts a failure and returns how long to wait: half the exponential delay
+    /// plus a random share of the other half
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = BASE_DELAY
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_DELAY);
+        self.attempts += 1;
+        ceiling / 2 + ceiling.mul_f64(rand::random_range(0.0..0.5))
+    }
+}
+
+/// Tracks retries across all workers: how often each chunk failed, which chunks
+/// ran out of attempts, and whether the upload was abandoned altogether
+pub struct RetryBudget {
+    limit: u32,
+    attempts: Mutex<HashMap<u64, u32>>,
+    failed: Mutex<Vec<(u64, String)>>,
+    aborted: Mutex<Option<String>>,
+    retries: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(limit: u32) -> Self {
+        RetryBudget {
+            limit,
+            attempts: Mutex::new(HashMap::new()),
+            failed: Mutex::new(Vec::new()),
+            aborted: Mutex::new(None),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Records a failed attempt at a chunk. Returns whether it may be tried again;
+    /// if not, the chunk is recorded as failed with `reason`.
+    pub fn retry(&self, chunk_index: u64, reason: &str) -> bool {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let count = attempts.entry(chunk_index).or_insert(0);
+        *count += 1;
+        if *count > self.limit {
+            self.failed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((chunk_index, reason.to_string()));
+            return false;
+        }
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Counts a retry that is not tied to a chunk, such as a reconnect
+    pub fn count_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stops every worker; the first reason given is kept
+    pub fn abort(&self, reason: String) {
+        self.aborted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(reason);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.abort_reason().is_some()
+    }
+
+    pub fn abort_reason(&self) -> Option<String> {
+        self.aborted.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Chunks that ran out of attempts, in index order
+    pub fn failures(&self) -> Vec<(u64, String)> {
+        let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        failed.sort_by_key(|(i, _)| *i);
+        failed
+    }
+
+    pub fn total_retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+}
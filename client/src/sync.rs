@@ -0,0 +1,82 @@
+use crate::connection::{Client, reply_error};
+use crate::upload::StoredFile;
+use sha2::{Digest, Sha256};
+use shared::delta::{self, Delta};
+use shared::{
+    ENCRYPTION_KEY, Message, ParaFlowError, compression, encryption, hash_chunk, read_message,
+    send_message,
+};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+impl Client {
+    /// Rebuilds `remote_name` on the server from the copy it already has: fetches the
+    /// block signatures, then streams copy instructions and the bytes of `path` that
+    /// changed. `bytes_sent` of the result counts only those changed bytes.
+    pub fn sync(&self, path: &Path, remote_name: &str) -> Result<StoredFile, ParaFlowError> {
+        let started = Instant::now();
+        let (mut stream, negotiated) = self.connect()?;
+        send_message(
+            &mut stream,
+            &Message::SyncRequest {
+                file_name: remote_name.to_string(),
+            },
+        )?;
+        let (block_size, blocks) = match read_message(&mut stream)? {
+            Message::Signature { block_size, blocks } => (block_size, blocks),
+            other => return Err(reply_error(other)),
+        };
+        if block_size == 0 {
+            return Err(ParaFlowError::ProtocolError(
+                "Server chose an invalid block size".into(),
+            ));
+        }
+
+        let mut file_hasher = Sha256::new();
+        std::io::copy(&mut File::open(path)?, &mut file_hasher)?;
+        let file_hash = hex::encode(file_hasher.finalize());
+
+        let (mut copied, mut literal) = (0u64, 0u64);
+        delta::compute_delta(File::open(path)?, block_size, &blocks, |op| match op {
+            Delta::Copy { offset, len } => {
+                copied += len;
+                send_message(&mut stream, &Message::DeltaCopy { offset, len })
+            }
+            Delta::Literal(data) => {
+                literal += data.len() as u64;
+                let (compression, payload) = compression::compress_chunk(data, negotiated);
+                let encrypted = encryption::encrypt_chunk(&payload, &ENCRYPTION_KEY)
+                    .map_err(ParaFlowError::EncryptionError)?;
+                send_message(
+                    &mut stream,
+                    &Message::DeltaData {
+                        size: encrypted.len(),
+                        hash: hash_chunk(&encrypted),
+                        compression,
+                    },
+                )?;
+                stream.write_all(&encrypted)?;
+                Ok(())
+            }
+        })?;
+
+        send_message(&mut stream, &Message::DeltaComplete { file_hash })?;
+        match read_message(&mut stream)? {
+            Message::CompleteAck {
+                file_name,
+                file_hash,
+            } => Ok(StoredFile {
+                file_name,
+                file_hash,
+                size: copied + literal,
+                bytes_sent: literal,
+                retries: 0,
+                elapsed: started.elapsed(),
+            }),
+            Message::CompleteFailed { reason, .. } => Err(ParaFlowError::TransferFailed(reason)),
+            other => Err(reply_error(other)),
+        }
+    }
+}
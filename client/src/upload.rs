@@ -0,0 +1,1148 @@
+use crate::concurrency::Controller;
+use crate::connection::{Client, connect_and_auth, reply_error};
+use crate::progress::{CancelHandle, NoProgress, Progress};
+use crate::ratelimit::{self, RateLimiter};
+use crate::reader::{BufferPool, FileReader};
+use crate::retry::{Backoff, RetryBudget};
+use sha2::{Digest, Sha256};
+use shared::compression::{self, Compression};
+use shared::{
+    ENCRYPTION_KEY, Message, ParaFlowError, encryption, hash_chunk, read_message, send_message,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Chunks read ahead from a stream per worker beyond its window, bounding memory use
+const READ_AHEAD_PER_WORKER: usize = 1;
+
+/// Threads reading chunks for the encoders. A positional read is cheap next to
+/// encoding, so a couple keep the encoders fed while one waits on the disk.
+const READERS: usize = 2;
+
+/// A file or stream to upload, and how to report on it
+pub struct UploadJob {
+    input: Input,
+    remote_name: String,
+    progress: Arc<dyn Progress>,
+    cancel: CancelHandle,
+}
+
+enum Input {
+    Path(PathBuf),
+    Reader(Box<dyn Read>),
+}
+
+impl UploadJob {
+    /// Uploads the file at `path`, stored under its file name (without the
+    /// directories) unless renamed. Anything that is not a regular file, such as a
+    /// FIFO, is streamed.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let remote_name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+        Self::new(Input::Path(path), remote_name)
+    }
+
+    /// Uploads everything `reader` yields until end of file as `remote_name`
+    pub fn reader(reader: impl Read + 'static, remote_name: impl Into<String>) -> Self {
+        Self::new(Input::Reader(Box::new(reader)), remote_name.into())
+    }
+
+    fn new(input: Input, remote_name: String) -> Self {
+        UploadJob {
+            input,
+            remote_name,
+            progress: Arc::new(NoProgress),
+            cancel: CancelHandle::new(),
+        }
+    }
+
+    /// Stores the upload under `name` instead; `/` separates directories, which the
+    /// server creates as needed
+    pub fn remote_name(mut self, name: impl Into<String>) -> Self {
+        self.remote_name = name.into();
+        self
+    }
+
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Stops the upload when `handle` is cancelled
+    pub fn cancel_with(mut self, handle: CancelHandle) -> Self {
+        self.cancel = handle;
+        self
+    }
+}
+
+/// What the server confirmed after a successful upload, and what it took
+#[derive(Debug)]
+pub struct StoredFile {
+    /// Name the file was stored under, which differs from the request when renamed
+    pub file_name: String,
+    pub file_hash: String,
+    pub size: u64,
+    /// Bytes actually transferred; chunks the server already had are not counted
+    pub bytes_sent: u64,
+    pub retries: u64,
+    pub elapsed: Duration,
+}
+
+/// Where encoders get chunk contents from
+enum Source {
+    /// A regular file, read at each chunk's offset
+    File(FileReader),
+    /// Chunks read ahead from a pipe, held until the server has acknowledged them
+    Stream(StreamBuffer),
+}
+
+/// Read-ahead chunks of a stream; the reader blocks while it is full, so a fast
+/// producer cannot outrun the upload
+struct StreamBuffer {
+    chunks: Mutex<HashMap<u64, Vec<u8>>>,
+    freed: Condvar,
+    capacity: usize,
+    finished: AtomicBool,
+}
+
+impl Source {
+    /// Replaces the contents of `buffer` with chunk `chunk_index`
+    fn read(&self, chunk_index: u64, chunk_size: u64, buffer: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Source::File(reader) => reader.read_chunk(chunk_index, chunk_size, buffer),
+            Source::Stream(stream) => {
+                let chunks = stream.chunks.lock().unwrap();
+                let chunk = chunks
+                    .get(&chunk_index)
+                    .expect("queued stream chunks stay buffered until acknowledged");
+                buffer.clear();
+                buffer.extend_from_slice(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    /// Drops an acknowledged chunk, making room for the stream reader
+    fn release(&self, chunk_index: u64) {
+        if let Source::Stream(buffer) = self {
+            buffer.chunks.lock().unwrap().remove(&chunk_index);
+            buffer.freed.notify_all();
+        }
+    }
+
+    /// True while chunks may still be added to the queue
+    fn has_more(&self) -> bool {
+        match self {
+            Source::File(_) => false,
+            Source::Stream(buffer) => !buffer.finished.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// One file's share of a pipeline
+struct Upload {
+    upload_id: String,
+    chunk_size: u64,
+    source: Source,
+    /// Chunk failures of this file; aborting it stops only this file
+    budget: RetryBudget,
+    bytes_sent: AtomicU64,
+    progress: Arc<dyn Progress>,
+    cancel: CancelHandle,
+}
+
+impl Upload {
+    fn stopped(&self) -> bool {
+        self.budget.is_aborted() || self.cancel.is_cancelled()
+    }
+}
+
+/// Everything the threads of one batch share. Chunks of every file go through
+/// the same stages: readers take them from `queue` and read them into `loaded`,
+/// encoders compress, encrypt and hash them into `ready`, and network workers send
+/// them from there, so disk reads, CPU work and the transfer all overlap.
+struct Pipeline {
+    uploads: Vec<Upload>,
+    negotiated: Compression,
+    server_addr: String,
+    user: String,
+    secret: String,
+    /// (index into `uploads`, chunk index)
+    queue: Mutex<Vec<(usize, u64)>>,
+    controller: Controller,
+    limiter: Option<RateLimiter>,
+    /// Chunks a connection may have sent without a verdict yet
+    window: usize,
+    /// Chunks read and waiting for an encoder, at most `loaded_capacity`
+    loaded: Mutex<VecDeque<Loaded>>,
+    loaded_changed: Condvar,
+    loaded_capacity: usize,
+    /// Encoded chunks waiting for a connection, at most `ready_capacity`
+    ready: Mutex<VecDeque<Encoded>>,
+    ready_changed: Condvar,
+    ready_capacity: usize,
+    /// Chunks a reader has taken from the queue but not yet loaded
+    reading: AtomicUsize,
+    /// Chunks an encoder has taken from `loaded` but not yet made ready
+    encoding: AtomicUsize,
+    /// Network workers still running; readers and encoders stop once none are left
+    sending: AtomicUsize,
+    encoders: usize,
+    /// Plaintext buffers, passed from readers to encoders
+    buffers: BufferPool,
+    /// Encrypted bodies, passed from encoders to network workers
+    bodies: BufferPool,
+    /// Connection failures; aborting it stops every file
+    budget: RetryBudget,
+}
+
+impl Pipeline {
+    fn has_work(&self) -> bool {
+        !self.drained()
+    }
+
+    /// True once every chunk has left the queue, the readers, `loaded`, the encoders
+    /// and `ready`. Checked in the order chunks move, so one passing between stages
+    /// is never missed.
+    fn drained(&self) -> bool {
+        !self.has_more()
+            && self.queue.lock().unwrap().is_empty()
+            && self.reading.load(Ordering::Acquire) == 0
+            && self.loaded.lock().unwrap().is_empty()
+            && self.encoding.load(Ordering::Acquire) == 0
+            && self.ready.lock().unwrap().is_empty()
+    }
+
+    /// True while a network worker may still take chunks from `ready`
+    fn sending(&self) -> bool {
+        self.sending.load(Ordering::Acquire) > 0 && !self.stopped()
+    }
+
+    /// Takes the next encoded chunk, waiting briefly for one if `wait` is set
+    fn next_ready(&self, wait: bool) -> Option<Encoded> {
+        let mut ready = self.ready.lock().unwrap();
+        if ready.is_empty() && wait {
+            ready = self
+                .ready_changed
+                .wait_timeout(ready, Duration::from_millis(20))
+                .unwrap()
+                .0;
+        }
+        let chunk = ready.pop_front();
+        if chunk.is_some() {
+            self.ready_changed.notify_all();
+        }
+        chunk
+    }
+
+    /// True while a stream may still add chunks to the queue
+    fn has_more(&self) -> bool {
+        self.uploads.iter().any(|u| u.source.has_more())
+    }
+
+    /// True once workers should stop taking chunks
+    fn stopped(&self) -> bool {
+        self.budget.is_aborted() || self.uploads.iter().all(Upload::stopped)
+    }
+
+    /// Reports an event that concerns the whole pipeline to every file
+    fn broadcast(&self, report: impl Fn(&dyn Progress)) {
+        for upload in &self.uploads {
+            report(upload.progress.as_ref());
+        }
+    }
+}
+
+/// What the server said about a chunk
+enum ChunkReply {
+    Ack,
+    Nack,
+}
+
+/// A chunk sent on a connection whose verdict has not arrived yet
+struct InFlight {
+    file: usize,
+    chunk_index: u64,
+    bytes: u64,
+    sent_at: Instant,
+}
+
+/// A chunk read into a pooled buffer, waiting for an encoder
+struct Loaded {
+    file: usize,
+    chunk_index: u64,
+    data: Vec<u8>,
+}
+
+/// A chunk read, compressed, encrypted and hashed, waiting for a connection
+struct Encoded {
+    file: usize,
+    chunk_index: u64,
+    /// Plaintext length, for progress and throughput
+    bytes: u64,
+    compression: Compression,
+    hash: String,
+    body: Vec<u8>,
+}
+
+/// Turns a loaded chunk into what goes on the wire, encrypting into a pooled body.
+/// Uncompressed chunks are encrypted straight from the read buffer.
+fn encode(p: &Pipeline, chunk: &Loaded) -> Result<Encoded, ParaFlowError> {
+    let (compression, payload) = compression::compress_chunk(&chunk.data, p.negotiated);
+    let mut body = p.bodies.take();
+    encryption::encrypt_chunk_into(&payload, &ENCRYPTION_KEY, &mut body)
+        .map_err(ParaFlowError::EncryptionError)?;
+    Ok(Encoded {
+        file: chunk.file,
+        chunk_index: chunk.chunk_index,
+        bytes: chunk.data.len() as u64,
+        compression,
+        hash: hash_chunk(&body),
+        body,
+    })
+}
+
+/// Sends one encoded chunk on an authenticated connection without waiting for the verdict
+fn send_chunk(
+    stream: &mut TcpStream,
+    upload_id: &str,
+    chunk: &Encoded,
+    limiter: Option<&RateLimiter>,
+) -> Result<(), ParaFlowError> {
+    send_message(
+        stream,
+        &Message::ChunkMeta {
+            upload_id: upload_id.to_string(),
+            chunk_index: chunk.chunk_index,
+            size: chunk.body.len(),
+            hash: chunk.hash.clone(),
+            compression: chunk.compression,
+        },
+    )?;
+
+    match limiter {
+        Some(limiter) => {
+            for piece in chunk.body.chunks(ratelimit::SLICE_SIZE) {
+                limiter.acquire(piece.len());
+                stream.write_all(piece)?;
+            }
+        }
+        None => stream.write_all(&chunk.body)?,
+    }
+    Ok(())
+}
+
+/// Reads the next verdict on a connection and takes its chunk out of the window.
+/// The server answers in order, so the oldest chunk with the named index is the
+/// one meant even when two files in flight share an index.
+fn read_verdict(
+    stream: &mut TcpStream,
+    in_flight: &mut VecDeque<InFlight>,
+) -> Result<(InFlight, ChunkReply), ParaFlowError> {
+    let (chunk_index, reply) = match read_message(stream)? {
+        Message::ChunkAck { chunk_index } => (chunk_index, ChunkReply::Ack),
+        Message::ChunkNack { chunk_index } => (chunk_index, ChunkReply::Nack),
+        Message::ShuttingDown => return Err(reply_error(Message::ShuttingDown)),
+        other => {
+            return Err(ParaFlowError::ProtocolError(format!(
+                "Unexpected reply to a chunk: {:?}",
+                other
+            )));
+        }
+    };
+    let position = in_flight
+        .iter()
+        .position(|c| c.chunk_index == chunk_index)
+        .ok_or_else(|| {
+            ParaFlowError::ProtocolError(format!(
+                "Verdict for chunk #{} which is not in flight",
+                chunk_index
+            ))
+        })?;
+    let chunk = in_flight.remove(position).expect("position is in range");
+    Ok((chunk, reply))
+}
+
+impl Client {
+    /// Runs `job` through the parallel chunk pipeline and returns what the server
+    /// stored once it has verified the whole-file hash
+    pub fn upload(&self, job: UploadJob) -> Result<StoredFile, ParaFlowError> {
+        self.upload_batch(vec![job])
+            .pop()
+            .expect("one result per job")
+    }
+
+    /// Uploads many jobs over one set of connections: files are announced on a
+    /// single setup connection, then the chunks of all of them share one worker
+    /// pool. Streams follow one at a time. Returns one result per job, in order.
+    pub fn upload_batch(&self, jobs: Vec<UploadJob>) -> Vec<Result<StoredFile, ParaFlowError>> {
+        let mut results: Vec<Option<Result<StoredFile, ParaFlowError>>> =
+            jobs.iter().map(|_| None).collect();
+        let mut files = Vec::new();
+        let mut streams: Vec<(usize, Box<dyn Read>, Job)> = Vec::new();
+        for (i, job) in jobs.into_iter().enumerate() {
+            let UploadJob {
+                input,
+                remote_name,
+                progress,
+                cancel,
+            } = job;
+            let job = Job {
+                remote_name,
+                progress,
+                cancel,
+            };
+            match input {
+                Input::Path(path) => match std::fs::metadata(&path) {
+                    Ok(metadata) if metadata.is_file() => files.push((i, path, job)),
+                    Ok(_) => match File::open(&path) {
+                        Ok(file) => streams.push((i, Box::new(file), job)),
+                        Err(e) => results[i] = Some(Err(e.into())),
+                    },
+                    Err(e) => results[i] = Some(Err(e.into())),
+                },
+                Input::Reader(reader) => streams.push((i, reader, job)),
+            }
+        }
+
+        for (i, result) in upload_files(self, files) {
+            results[i] = Some(result);
+        }
+        for (i, reader, job) in streams {
+            results[i] = Some(upload_stream(self, reader, job));
+        }
+        results
+            .into_iter()
+            .map(|r| r.expect("every job has a result"))
+            .collect()
+    }
+}
+
+/// The parts of an `UploadJob` the pipeline needs once its input is taken apart
+struct Job {
+    remote_name: String,
+    progress: Arc<dyn Progress>,
+    cancel: CancelHandle,
+}
+
+/// A file the server has accepted, waiting for the worker phase
+struct Announced {
+    job_index: usize,
+    upload: Upload,
+    /// Chunks the server does not have yet
+    pending: Vec<u64>,
+    completion: Message,
+    size: u64,
+}
+
+/// Uploads regular files through one shared pipeline. Returns a result per file,
+/// tagged with the job index it came with.
+fn upload_files(
+    client: &Client,
+    files: Vec<(usize, PathBuf, Job)>,
+) -> Vec<(usize, Result<StoredFile, ParaFlowError>)> {
+    let mut results = Vec::new();
+    if files.is_empty() {
+        return results;
+    }
+    let started = Instant::now();
+
+    // --- 1. SETUP PHASE: announce every file on one connection ---
+    let mut negotiated = None;
+    let mut setup: Option<TcpStream> = None;
+    let mut announced = Vec::new();
+    for (job_index, path, job) in files {
+        if setup.is_none() {
+            match client.connect() {
+                Ok((stream, compression)) => {
+                    setup = Some(stream);
+                    negotiated.get_or_insert(compression);
+                }
+                Err(e) => {
+                    results.push((job_index, Err(e)));
+                    continue;
+                }
+            }
+        }
+        let stream = setup.as_mut().expect("connected above");
+        let compression = negotiated.expect("set on first connect");
+        match announce(stream, compression, &path, job) {
+            Ok(file) => announced.push(Announced { job_index, ..file }),
+            Err(e) => {
+                // A refusal leaves the connection usable; anything else may not
+                if !matches!(e, ParaFlowError::ServerError(_)) {
+                    setup = None;
+                }
+                results.push((job_index, Err(e)));
+            }
+        }
+    }
+    drop(setup);
+    let Some(negotiated) = negotiated else {
+        return results;
+    };
+
+    // --- 2. WORKER PHASE ---
+    let mut queue = Vec::new();
+    let mut uploads = Vec::new();
+    let mut completions = Vec::new();
+    for (k, file) in announced.into_iter().enumerate() {
+        queue.extend(file.pending.into_iter().map(|i| (k, i)));
+        uploads.push(file.upload);
+        completions.push((file.job_index, file.completion, file.size));
+    }
+    // Workers pop from the end; start with the first file
+    queue.reverse();
+    let pipeline = Arc::new(Pipeline::new(client, uploads, negotiated, queue));
+    for h in spawn_workers(&pipeline) {
+        h.join().unwrap();
+    }
+
+    // --- 3. COMPLETE PHASE, again on one connection ---
+    let mut connection = None;
+    for (k, (job_index, completion, size)) in completions.into_iter().enumerate() {
+        let result = check_failures(&pipeline, k)
+            .and_then(|()| complete(&pipeline, k, &mut connection, &completion, size, started));
+        results.push((job_index, result));
+    }
+    results
+}
+
+/// Opens an upload for `path` on the setup connection, hashes its chunks and learns
+/// which ones the server already has
+fn announce(
+    stream: &mut TcpStream,
+    negotiated: Compression,
+    path: &Path,
+    job: Job,
+) -> Result<Announced, ParaFlowError> {
+    let file_size = std::fs::metadata(path)?.len();
+    job.progress.length(file_size);
+    let (upload_id, chunk_size) = open_upload(
+        stream,
+        &Message::InitUpload {
+            file_name: job.remote_name.clone(),
+            total_size: file_size,
+        },
+    )?;
+    job.progress.started(&upload_id, chunk_size, negotiated);
+    let total_chunks = file_size.div_ceil(chunk_size);
+
+    // Hash every chunk so the server can tell us which ones it already has,
+    // and the whole file so it can verify the merged result
+    let reader = FileReader::open(path)?;
+    let mut file_hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut chunk = Vec::new();
+    for i in 0..total_chunks {
+        reader.read_chunk(i, chunk_size, &mut chunk)?;
+        file_hasher.update(&chunk);
+        chunk_hashes.push(hash_chunk(&chunk));
+    }
+    let file_hash = hex::encode(file_hasher.finalize());
+
+    send_message(
+        stream,
+        &Message::ChunkManifest {
+            upload_id: upload_id.clone(),
+            chunk_hashes,
+        },
+    )?;
+    let known_chunks = match read_message(stream)? {
+        Message::ManifestAck { known_chunks } => known_chunks,
+        other => return Err(reply_error(other)),
+    };
+    if !known_chunks.is_empty() {
+        let known_bytes = known_chunks
+            .iter()
+            .map(|&i| file_size.min((i + 1) * chunk_size) - i * chunk_size)
+            .sum();
+        job.progress
+            .deduplicated(known_chunks.len() as u64, total_chunks, known_bytes);
+    }
+
+    Ok(Announced {
+        job_index: 0,
+        pending: (0..total_chunks)
+            .filter(|i| !known_chunks.contains(i))
+            .collect(),
+        completion: Message::Complete {
+            upload_id: upload_id.clone(),
+            file_name: job.remote_name.clone(),
+            total_chunks,
+            file_hash,
+        },
+        size: file_size,
+        upload: Upload::new(job, upload_id, chunk_size, Source::File(reader)),
+    })
+}
+
+/// Uploads everything `reader` yields until end of file. The length is not known
+/// up front, so chunks are hashed as they are read and announced at completion.
+fn upload_stream(
+    client: &Client,
+    mut reader: impl Read,
+    job: Job,
+) -> Result<StoredFile, ParaFlowError> {
+    let started = Instant::now();
+
+    // --- 1. SETUP PHASE ---
+    let (mut setup_stream, negotiated) = client.connect()?;
+    let (upload_id, chunk_size) = open_upload(
+        &mut setup_stream,
+        &Message::InitStream {
+            file_name: job.remote_name.clone(),
+        },
+    )?;
+    drop(setup_stream);
+    job.progress.started(&upload_id, chunk_size, negotiated);
+
+    // --- 2. WORKER PHASE, fed while reading ---
+    let remote_name = job.remote_name.clone();
+    let source = Source::Stream(StreamBuffer {
+        chunks: Mutex::new(HashMap::new()),
+        freed: Condvar::new(),
+        capacity: (client.window + READ_AHEAD_PER_WORKER) * client.max_workers(),
+        finished: AtomicBool::new(false),
+    });
+    let upload = Upload::new(job, upload_id.clone(), chunk_size, source);
+    let pipeline = Arc::new(Pipeline::new(client, vec![upload], negotiated, Vec::new()));
+    let handles = spawn_workers(&pipeline);
+
+    let upload = &pipeline.uploads[0];
+    let Source::Stream(buffer) = &upload.source else {
+        unreachable!("stream uploads use a stream source");
+    };
+    let mut file_hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut total_size = 0u64;
+    loop {
+        let mut chunk = Vec::with_capacity(chunk_size as usize);
+        if let Err(e) = (&mut reader).take(chunk_size).read_to_end(&mut chunk) {
+            upload
+                .budget
+                .abort(format!("Reading the input failed: {}", e));
+            break;
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        file_hasher.update(&chunk);
+        chunk_hashes.push(hash_chunk(&chunk));
+        total_size += chunk.len() as u64;
+        upload.progress.length(total_size);
+
+        let mut chunks = buffer.chunks.lock().unwrap();
+        while chunks.len() >= buffer.capacity && !pipeline.stopped() {
+            chunks = buffer
+                .freed
+                .wait_timeout(chunks, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        if pipeline.stopped() {
+            break;
+        }
+        let chunk_index = chunk_hashes.len() as u64 - 1;
+        chunks.insert(chunk_index, chunk);
+        drop(chunks);
+        pipeline.queue.lock().unwrap().push((0, chunk_index));
+    }
+    buffer.finished.store(true, Ordering::Release);
+
+    for h in handles {
+        h.join().unwrap();
+    }
+    check_failures(&pipeline, 0)?;
+
+    // --- 3. COMPLETE PHASE ---
+    complete(
+        &pipeline,
+        0,
+        &mut None,
+        &Message::CompleteStream {
+            upload_id,
+            file_name: remote_name,
+            total_size,
+            chunk_hashes,
+            file_hash: hex::encode(file_hasher.finalize()),
+        },
+        total_size,
+        started,
+    )
+}
+
+/// Sends the request that opens an upload and returns the upload id and the chunk
+/// size the server picked
+fn open_upload(stream: &mut TcpStream, init: &Message) -> Result<(String, u64), ParaFlowError> {
+    send_message(stream, init)?;
+    let (upload_id, chunk_size) = match read_message(stream)? {
+        Message::InitAck {
+            upload_id,
+            chunk_size,
+        } => (upload_id, chunk_size),
+        other => return Err(reply_error(other)),
+    };
+    if chunk_size == 0 {
+        return Err(ParaFlowError::ProtocolError(
+            "Server chose an invalid chunk size".into(),
+        ));
+    }
+    Ok((upload_id, chunk_size))
+}
+
+impl Upload {
+    fn new(job: Job, upload_id: String, chunk_size: u64, source: Source) -> Self {
+        Upload {
+            upload_id,
+            chunk_size,
+            source,
+            budget: RetryBudget::new(0),
+            bytes_sent: AtomicU64::new(0),
+            progress: job.progress,
+            cancel: job.cancel,
+        }
+    }
+}
+
+impl Pipeline {
+    fn new(
+        client: &Client,
+        mut uploads: Vec<Upload>,
+        negotiated: Compression,
+        queue: Vec<(usize, u64)>,
+    ) -> Self {
+        for upload in &mut uploads {
+            upload.budget = RetryBudget::new(client.retries);
+        }
+        let controller = Controller::new(client.threads, client.max_threads);
+        let workers = controller.max_workers();
+        let encoders = thread::available_parallelism()
+            .map_or(2, |n| n.get())
+            .min(workers);
+        let ready_capacity = workers + encoders;
+        Pipeline {
+            uploads,
+            negotiated,
+            server_addr: client.address.clone(),
+            user: client.user.clone(),
+            secret: client.secret.clone(),
+            queue: Mutex::new(queue),
+            controller,
+            limiter: client.limit_rate.clone().map(RateLimiter::new),
+            window: client.window,
+            loaded: Mutex::new(VecDeque::new()),
+            loaded_changed: Condvar::new(),
+            loaded_capacity: encoders,
+            ready: Mutex::new(VecDeque::new()),
+            ready_changed: Condvar::new(),
+            ready_capacity,
+            reading: AtomicUsize::new(0),
+            encoding: AtomicUsize::new(0),
+            sending: AtomicUsize::new(workers),
+            encoders,
+            // Each reader and encoder holds one plaintext buffer, plus those in `loaded`
+            buffers: BufferPool::new(READERS + 2 * encoders),
+            // Bodies wait in `ready`, in encoders and in workers sending them
+            bodies: BufferPool::new(ready_capacity + encoders + workers),
+            budget: RetryBudget::new(client.retries),
+        }
+    }
+}
+
+/// Starts the readers, the encoders and one network worker per potential
+/// connection, plus the concurrency monitor in auto mode
+fn spawn_workers(pipeline: &Arc<Pipeline>) -> Vec<JoinHandle<()>> {
+    let mut handles = vec![];
+    for _ in 0..READERS {
+        let p = Arc::clone(pipeline);
+        handles.push(thread::spawn(move || run_reader(&p)));
+    }
+    for _ in 0..pipeline.encoders {
+        let p = Arc::clone(pipeline);
+        handles.push(thread::spawn(move || run_encoder(&p)));
+    }
+    for worker_id in 0..pipeline.controller.max_workers() {
+        let p = Arc::clone(pipeline);
+        handles.push(thread::spawn(move || {
+            run_worker(&p, worker_id);
+            p.sending.fetch_sub(1, Ordering::AcqRel);
+            p.ready_changed.notify_all();
+            p.broadcast(|progress| progress.worker_status(worker_id, "Done"));
+        }));
+    }
+
+    // The monitor adjusts the connection limit once per interval until workers finish
+    if pipeline.controller.is_adaptive() {
+        let p = Arc::clone(pipeline);
+        thread::spawn(move || {
+            while p.has_work() && !p.stopped() {
+                thread::sleep(Duration::from_secs(1));
+                if let Some(limit) = p.controller.adjust() {
+                    p.broadcast(|progress| progress.concurrency(limit));
+                }
+            }
+        });
+    }
+    handles
+}
+
+/// Reads queued chunks into pooled buffers for the encoders, for as long as any
+/// network worker runs
+fn run_reader(p: &Pipeline) {
+    while p.sending() {
+        let next = {
+            let mut queue = p.queue.lock().unwrap();
+            let next = queue.pop();
+            // Counted before the lock is released, so `drained` never misses it
+            if next.is_some() {
+                p.reading.fetch_add(1, Ordering::AcqRel);
+            }
+            next
+        };
+        let Some((file, chunk_index)) = next else {
+            thread::sleep(Duration::from_millis(5));
+            continue;
+        };
+
+        let u = &p.uploads[file];
+        if u.stopped() {
+            u.source.release(chunk_index);
+        } else {
+            let mut data = p.buffers.take();
+            match u.source.read(chunk_index, u.chunk_size, &mut data) {
+                Ok(()) => {
+                    let mut loaded = p.loaded.lock().unwrap();
+                    while loaded.len() >= p.loaded_capacity && p.sending() {
+                        loaded = p
+                            .loaded_changed
+                            .wait_timeout(loaded, Duration::from_millis(20))
+                            .unwrap()
+                            .0;
+                    }
+                    loaded.push_back(Loaded {
+                        file,
+                        chunk_index,
+                        data,
+                    });
+                    p.loaded_changed.notify_all();
+                }
+                // Retrying will not make a local read succeed
+                Err(e) => {
+                    p.buffers.give(data);
+                    u.budget
+                        .abort(format!("Chunk #{} could not be read: {}", chunk_index, e));
+                    u.source.release(chunk_index);
+                }
+            }
+        }
+        p.reading.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Encodes loaded chunks ahead of the network workers for as long as any of them runs
+fn run_encoder(p: &Pipeline) {
+    while p.sending() {
+        let next = {
+            let mut loaded = p.loaded.lock().unwrap();
+            if loaded.is_empty() {
+                loaded = p
+                    .loaded_changed
+                    .wait_timeout(loaded, Duration::from_millis(20))
+                    .unwrap()
+                    .0;
+            }
+            let next = loaded.pop_front();
+            // Counted before the lock is released, so `drained` never misses it
+            if next.is_some() {
+                p.encoding.fetch_add(1, Ordering::AcqRel);
+                p.loaded_changed.notify_all();
+            }
+            next
+        };
+        let Some(chunk) = next else {
+            continue;
+        };
+
+        let u = &p.uploads[chunk.file];
+        if u.stopped() {
+            u.source.release(chunk.chunk_index);
+        } else {
+            match encode(p, &chunk) {
+                Ok(encoded) => {
+                    let mut ready = p.ready.lock().unwrap();
+                    while ready.len() >= p.ready_capacity && p.sending() {
+                        ready = p
+                            .ready_changed
+                            .wait_timeout(ready, Duration::from_millis(20))
+                            .unwrap()
+                            .0;
+                    }
+                    ready.push_back(encoded);
+                    p.ready_changed.notify_all();
+                }
+                // Retrying will not make encryption succeed
+                Err(e) => {
+                    u.budget.abort(format!(
+                        "Chunk #{} could not be encoded: {}",
+                        chunk.chunk_index, e
+                    ));
+                    u.source.release(chunk.chunk_index);
+                }
+            }
+        }
+        p.buffers.give(chunk.data);
+        p.encoding.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn run_worker(p: &Pipeline, worker_id: usize) {
+    let mut connection: Option<TcpStream> = None;
+    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+    let mut backoff = Backoff::default();
+
+    while !p.stopped() {
+        let active = p.controller.is_active(worker_id);
+        // Parked workers give up their connection once its window has drained
+        if !active && in_flight.is_empty() {
+            if connection.take().is_some() {
+                p.broadcast(|progress| progress.worker_status(worker_id, "Parked"));
+            }
+            if !p.has_work() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        // Keep the window full before waiting on a verdict
+        if active && in_flight.len() < p.window {
+            match p.next_ready(in_flight.is_empty()) {
+                Some(chunk) => {
+                    let u = &p.uploads[chunk.file];
+                    if u.stopped() {
+                        u.source.release(chunk.chunk_index);
+                        p.bodies.give(chunk.body);
+                        continue;
+                    }
+
+                    if connection.is_none() {
+                        let login =
+                            connect_and_auth(&p.server_addr, &p.user, &p.secret, &[p.negotiated]);
+                        match login {
+                            Ok((stream, _)) => {
+                                u.progress.worker_status(worker_id, "Connected");
+                                connection = Some(stream);
+                            }
+                            Err(e) => {
+                                p.ready.lock().unwrap().push_front(chunk);
+                                // The server is down for everyone, not just this worker
+                                if backoff.attempts() >= p.budget.limit() {
+                                    p.budget.abort(format!("Could not reach server: {}", e));
+                                    break;
+                                }
+                                u.budget.count_retry();
+                                u.progress
+                                    .retry(None, &format!("Could not connect: {}", e), true);
+                                u.progress
+                                    .worker_status(worker_id, &format!("⚠️ Reconnecting: {}", e));
+                                thread::sleep(backoff.next_delay());
+                                continue;
+                            }
+                        }
+                    }
+                    let stream = connection.as_mut().expect("connected above");
+
+                    u.progress.worker_status(
+                        worker_id,
+                        &format!("Uploading Chunk #{}", chunk.chunk_index),
+                    );
+                    let sent = send_chunk(stream, &u.upload_id, &chunk, p.limiter.as_ref());
+                    p.bodies.give(chunk.body);
+                    match sent {
+                        Ok(()) => in_flight.push_back(InFlight {
+                            file: chunk.file,
+                            chunk_index: chunk.chunk_index,
+                            bytes: chunk.bytes,
+                            sent_at: Instant::now(),
+                        }),
+                        Err(e) => {
+                            // The stream may be mid-message; start over on a fresh one
+                            connection = None;
+                            let failure = format!("Connection lost: {}", e);
+                            requeue(p, worker_id, chunk.file, chunk.chunk_index, &failure);
+                            for chunk in in_flight.drain(..) {
+                                requeue(p, worker_id, chunk.file, chunk.chunk_index, &failure);
+                            }
+                            thread::sleep(backoff.next_delay());
+                        }
+                    }
+                    continue;
+                }
+                // Encoders may still be working, or a stream reading ahead
+                None if in_flight.is_empty() && !p.drained() => continue,
+                None if in_flight.is_empty() => break,
+                None => {}
+            }
+        }
+
+        let stream = connection
+            .as_mut()
+            .expect("chunks in flight have a connection");
+        match read_verdict(stream, &mut in_flight) {
+            Ok((chunk, ChunkReply::Ack)) => {
+                let u = &p.uploads[chunk.file];
+                let rtt = chunk.sent_at.elapsed();
+                p.controller.record_ack(chunk.bytes, rtt);
+                u.bytes_sent.fetch_add(chunk.bytes, Ordering::Relaxed);
+                u.progress.chunk_done(chunk.chunk_index, chunk.bytes, rtt);
+                u.source.release(chunk.chunk_index);
+                backoff.reset();
+            }
+            Ok((chunk, ChunkReply::Nack)) => {
+                p.controller.record_nack();
+                requeue(
+                    p,
+                    worker_id,
+                    chunk.file,
+                    chunk.chunk_index,
+                    "Rejected by server",
+                );
+                thread::sleep(backoff.next_delay());
+            }
+            Err(e) => {
+                connection = None;
+                let failure = format!("Connection lost: {}", e);
+                for chunk in in_flight.drain(..) {
+                    requeue(p, worker_id, chunk.file, chunk.chunk_index, &failure);
+                }
+                thread::sleep(backoff.next_delay());
+            }
+        }
+    }
+}
+
+/// Hands a failed chunk back so any healthy worker can pick it up, unless it has
+/// run out of retries
+fn requeue(p: &Pipeline, worker_id: usize, file: usize, chunk_index: u64, failure: &str) {
+    let u = &p.uploads[file];
+    let will_retry = u.budget.retry(chunk_index, failure);
+    if will_retry {
+        p.queue.lock().unwrap().push((file, chunk_index));
+    } else {
+        u.source.release(chunk_index);
+    }
+    u.progress.retry(Some(chunk_index), failure, will_retry);
+    u.progress.worker_status(
+        worker_id,
+        &format!("⚠️ Chunk #{}: {}", chunk_index, failure),
+    );
+}
+
+/// Turns a cancelled upload, chunks that ran out of retries or an abandoned upload
+/// into an error for file `k`, reporting each failed chunk
+fn check_failures(p: &Pipeline, k: usize) -> Result<(), ParaFlowError> {
+    let u = &p.uploads[k];
+    let failures = u.budget.failures();
+    let unsent = p
+        .queue
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(file, _)| *file == k)
+        .count();
+    let success = failures.is_empty() && unsent == 0 && !u.stopped() && !p.budget.is_aborted();
+    u.progress.finished(success);
+    if success {
+        return Ok(());
+    }
+    if u.cancel.is_cancelled() {
+        return Err(ParaFlowError::Cancelled);
+    }
+
+    for (chunk_index, reason) in &failures {
+        u.progress.chunk_failed(*chunk_index, reason);
+    }
+    let mut summary = format!(
+        "{} chunks failed after {} retries (upload ID {})",
+        failures.len() + unsent,
+        u.budget.total_retries(),
+        u.upload_id
+    );
+    if let Some(reason) = u.budget.abort_reason().or_else(|| p.budget.abort_reason()) {
+        summary = format!("{}: {}", reason, summary);
+    }
+    Err(ParaFlowError::TransferFailed(summary))
+}
+
+/// Sends the completion request for file `k` and interprets the verdict. The
+/// connection is opened on first use and kept for the next file.
+fn complete(
+    p: &Pipeline,
+    k: usize,
+    connection: &mut Option<TcpStream>,
+    request: &Message,
+    size: u64,
+    started: Instant,
+) -> Result<StoredFile, ParaFlowError> {
+    let u = &p.uploads[k];
+    if connection.is_none() {
+        let mut backoff = Backoff::default();
+        *connection = loop {
+            match connect_and_auth(&p.server_addr, &p.user, &p.secret, &[]) {
+                Ok((stream, _)) => break Some(stream),
+                Err(e) if backoff.attempts() >= p.budget.limit() => return Err(e),
+                Err(_) => thread::sleep(backoff.next_delay()),
+            }
+        };
+    }
+    let stream = connection.as_mut().expect("connected above");
+    let reply = send_message(stream, request).and_then(|()| read_message(stream));
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => {
+            *connection = None;
+            return Err(e);
+        }
+    };
+
+    match reply {
+        Message::CompleteAck {
+            file_name,
+            file_hash,
+        } => Ok(StoredFile {
+            file_name,
+            file_hash,
+            size,
+            bytes_sent: u.bytes_sent.load(Ordering::Relaxed),
+            retries: u.budget.total_retries(),
+            elapsed: started.elapsed(),
+        }),
+        Message::CompleteFailed {
+            missing_chunks,
+            reason,
+        } if missing_chunks.is_empty() => Err(ParaFlowError::TransferFailed(format!(
+            "Merge failed: {}",
+            reason
+        ))),
+        Message::CompleteFailed {
+            missing_chunks,
+            reason,
+        } => Err(ParaFlowError::TransferFailed(format!(
+            "Merge failed: {} (missing chunks: {:?})",
+            reason, missing_chunks
+        ))),
+        other => Err(reply_error(other)),
+    }
+}
@@ -0,0 +1,117 @@
+use crate::connection::{Client, reply_error};
+use sha2::{Digest, Sha256};
+use shared::{Message, ParaFlowError, hash_chunk, read_message, send_message};
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+/// How a local file compares with the server's copy
+#[derive(Debug)]
+pub struct Verification {
+    pub file_name: String,
+    pub local_size: u64,
+    pub remote_size: u64,
+    pub local_hash: String,
+    pub remote_hash: String,
+    /// Chunk size the comparison used; zero when only whole-file hashes were compared
+    pub chunk_size: u64,
+    /// Byte ranges whose contents differ, merged where adjacent. Empty when the
+    /// files match or when no chunk size was given.
+    pub differing: Vec<Range<u64>>,
+}
+
+impl Verification {
+    pub fn matches(&self) -> bool {
+        self.local_hash == self.remote_hash
+    }
+}
+
+impl Client {
+    /// Compares `path` with the stored `remote_name` as the server has it on disk.
+    /// With a `chunk_size`, both sides also hash every chunk so the ranges that
+    /// differ can be named; nothing but hashes crosses the network.
+    pub fn verify(
+        &self,
+        path: &Path,
+        remote_name: &str,
+        chunk_size: Option<u64>,
+    ) -> Result<Verification, ParaFlowError> {
+        if chunk_size == Some(0) {
+            return Err(ParaFlowError::ProtocolError(
+                "Chunk size must be positive".into(),
+            ));
+        }
+        let (mut stream, _) = self.connect()?;
+        send_message(
+            &mut stream,
+            &Message::HashRequest {
+                file_name: remote_name.to_string(),
+                chunk_size,
+            },
+        )?;
+        // Hash the local side while the server hashes its copy
+        let local = hash_local(path, chunk_size);
+        let reply = read_message(&mut stream)?;
+        let (file_name, remote_size, remote_hash, remote_chunks) = match reply {
+            Message::HashReport {
+                file_name,
+                size,
+                file_hash,
+                chunk_hashes,
+            } => (file_name, size, file_hash, chunk_hashes),
+            other => return Err(reply_error(other)),
+        };
+        let (local_size, local_hash, local_chunks) = local?;
+
+        let mut verification = Verification {
+            file_name,
+            local_size,
+            remote_size,
+            local_hash,
+            remote_hash,
+            chunk_size: chunk_size.unwrap_or(0),
+            differing: Vec::new(),
+        };
+        if let Some(chunk_size) = chunk_size.filter(|_| !verification.matches()) {
+            let end = local_size.max(remote_size);
+            for i in 0..local_chunks.len().max(remote_chunks.len()) {
+                if local_chunks.get(i) == remote_chunks.get(i) {
+                    continue;
+                }
+                let start = i as u64 * chunk_size;
+                let stop = end.min(start + chunk_size);
+                match verification.differing.last_mut() {
+                    Some(last) if last.end == start => last.end = stop,
+                    _ => verification.differing.push(start..stop),
+                }
+            }
+        }
+        Ok(verification)
+    }
+}
+
+/// Size, whole-file hash and per-chunk hashes of a local file
+fn hash_local(
+    path: &Path,
+    chunk_size: Option<u64>,
+) -> Result<(u64, String, Vec<String>), ParaFlowError> {
+    let mut file = File::open(path)?;
+    let mut file_hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut size = 0;
+    let slice = chunk_size.unwrap_or(1024 * 1024);
+    loop {
+        let mut buffer = Vec::with_capacity(slice as usize);
+        (&mut file).take(slice).read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            break;
+        }
+        file_hasher.update(&buffer);
+        if chunk_size.is_some() {
+            chunk_hashes.push(hash_chunk(&buffer));
+        }
+        size += buffer.len() as u64;
+    }
+    Ok((size, hex::encode(file_hasher.finalize()), chunk_hashes))
+}
@@ -0,0 +1,259 @@
+use crate::output::{self, Reporter};
+use clap::Args;
+use client::{Client, ParaFlowError, UploadJob};
+use inotify::{EventMask, Inotify, WatchMask};
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A failed upload is attempted again after this long
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// What to watch for and what to do with a file once it is safely on the server
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Seconds a file must go without writes before it is uploaded
+    #[arg(long, default_value_t = 5)]
+    settle: u64,
+    /// Delete the local copy once the server has verified the upload
+    #[arg(long, conflicts_with = "move_to")]
+    delete: bool,
+    /// Move the local copy into this directory once the server has verified the upload
+    #[arg(long)]
+    move_to: Option<PathBuf>,
+}
+
+/// Watches `dir` (not recursively) and uploads every file once it has settled.
+/// Only returns if the watch itself fails.
+pub fn run(dir: &Path, client: &Client, args: &WatchArgs) -> io::Result<()> {
+    if let Some(target) = &args.move_to {
+        fs::create_dir_all(target)?;
+    }
+    let mut inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+    )?;
+
+    // Events arrive on a channel so the main loop can wake up when a file settles.
+    // `None` asks for a rescan because the kernel queue overflowed.
+    let (events_tx, events_rx) = mpsc::channel::<Option<OsString>>();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("❌ Reading file events failed: {}", e);
+                    return;
+                }
+            };
+            for event in events {
+                let message = if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    None
+                } else {
+                    match event.name {
+                        Some(name) => Some(name.to_owned()),
+                        None => continue,
+                    }
+                };
+                if events_tx.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let settle = Duration::from_secs(args.settle);
+    // When each pending file is next due for upload
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    // Files that were already waiting before we started are shipped too
+    rescan(dir, &mut pending, settle)?;
+    output::info(format!(
+        "👀 Watching {} ({} files pending)",
+        dir.display(),
+        pending.len()
+    ));
+    output::event("watching", json!({ "dir": dir, "pending": pending.len() }));
+
+    loop {
+        let timeout = pending
+            .values()
+            .min()
+            .map(|due| due.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::from_secs(3600));
+        match events_rx.recv_timeout(timeout) {
+            Ok(Some(name)) => {
+                let path = dir.join(name);
+                if is_candidate(&path) {
+                    pending.insert(path, Instant::now() + settle);
+                }
+            }
+            Ok(None) => rescan(dir, &mut pending, settle)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("File watch stopped"));
+            }
+        }
+
+        let now = Instant::now();
+        let due: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in due {
+            pending.remove(&path);
+            // Gone again before it settled, e.g. a temporary file that was renamed
+            if !path.is_file() {
+                continue;
+            }
+            if let Err(e) = ship(&path, client, args) {
+                // A refusal is reported once; the file is looked at again only
+                // if it changes
+                let retry = !output::is_permanent(&e);
+                if retry {
+                    output::warn(format!(
+                        "❌ {}: {} (retrying in {}s)",
+                        path.display(),
+                        e,
+                        RETRY_DELAY.as_secs()
+                    ));
+                } else {
+                    output::warn(format!("❌ {}: {} (not retrying)", path.display(), e));
+                }
+                output::event(
+                    "failed",
+                    json!({
+                        "action": "Upload",
+                        "path": path,
+                        "error": e.to_string(),
+                        "exit_code": output::exit_code(&e),
+                        "retry_in_secs": retry.then_some(RETRY_DELAY.as_secs()),
+                    }),
+                );
+                if retry {
+                    pending.insert(path, Instant::now() + RETRY_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Uploads one settled file and then deletes or moves it if asked to
+fn ship(path: &Path, client: &Client, args: &WatchArgs) -> Result<(), ParaFlowError> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let before = fingerprint(path)?;
+
+    output::info(format!("📦 Uploading {}", name));
+    let job = UploadJob::file(path)
+        .remote_name(&name)
+        .progress(Arc::new(Reporter::upload()));
+    let stored = client.upload(job)?;
+    if output::is_json() {
+        output::stored(&stored);
+    } else {
+        println!(
+            "✅ {} stored as {} (SHA-256 {})",
+            name, stored.file_name, stored.file_hash
+        );
+    }
+
+    // The server verified what we read; if the file moved on since, the newer
+    // content is still only local, so leave it for the next pass
+    if fingerprint(path)? != before {
+        output::info(format!(
+            "   {} changed during upload; keeping the local copy",
+            name
+        ));
+        return Ok(());
+    }
+    if args.delete {
+        fs::remove_file(path)?;
+        output::info("   Deleted local copy");
+        output::event("local_deleted", json!({ "path": path }));
+    } else if let Some(target) = &args.move_to {
+        let destination = target.join(&name);
+        match move_file(path, &destination) {
+            Ok(()) => {
+                output::info(format!("   Moved local copy to {}", destination.display()));
+                output::event(
+                    "local_moved",
+                    json!({ "path": path, "destination": destination }),
+                );
+            }
+            // The upload itself succeeded, so this is not worth a retry
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                output::warn(format!("⚠️ {}", e));
+                output::event(
+                    "local_kept",
+                    json!({ "path": path, "destination": destination, "error": e.to_string() }),
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Schedules every file currently in `dir`
+fn rescan(dir: &Path, pending: &mut HashMap<PathBuf, Instant>, settle: Duration) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_candidate(&path) {
+            pending
+                .entry(path)
+                .or_insert_with(|| Instant::now() + settle);
+        }
+    }
+    Ok(())
+}
+
+/// Regular files only; dot-files are usually temporaries still being written
+fn is_candidate(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+    !hidden && path.is_file()
+}
+
+fn fingerprint(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+/// Renames, falling back to copy and delete when the target is on another
+/// filesystem. Never replaces a file already at `to`.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists; keeping the local copy", to.display()),
+        ));
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut source = File::open(from)?;
+            let mut copy = OpenOptions::new().write(true).create_new(true).open(to)?;
+            let copied = io::copy(&mut source, &mut copy)
+                .and_then(|_| copy.set_permissions(source.metadata()?.permissions()))
+                .and_then(|_| copy.sync_all());
+            if let Err(e) = copied {
+                let _ = fs::remove_file(to);
+                return Err(e);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
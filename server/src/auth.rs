@@ -1,4 +1,5 @@
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 /// Generates a unique salt for the Challenge-Response handshake
@@ -6,18 +7,27 @@ pub fn generate_salt() -> String {
     Uuid::new_v4().to_string()
 }
 
-pub fn verify_user(username: &str, salt: &str, answer: &str) -> bool {
-    if username == "admin" {
-        // Load password from environment variable
-        let actual_pass = std::env::var("PARAFLOW_ADMIN_PASSWORD")
-            .unwrap_or_else(|_| "default_fallback_change_me".to_string());
+/// The users allowed in when the config file names none: `admin`, with the
+/// password from `PARAFLOW_ADMIN_PASSWORD`
+pub fn default_users() -> HashMap<String, String> {
+    let password = std::env::var("PARAFLOW_ADMIN_PASSWORD")
+        .unwrap_or_else(|_| "default_fallback_change_me".to_string());
+    HashMap::from([("admin".to_string(), password)])
+}
 
-        let combined = format!("{}{}", actual_pass, salt);
-        let mut hasher = Sha256::new();
-        hasher.update(combined.as_bytes());
-        let expected_hash = hex::encode(hasher.finalize());
+pub fn verify_user(
+    users: &HashMap<String, String>,
+    username: &str,
+    salt: &str,
+    answer: &str,
+) -> bool {
+    let Some(actual_pass) = users.get(username) else {
+        return false;
+    };
+    let combined = format!("{}{}", actual_pass, salt);
+    let mut hasher = Sha256::new();
+    hasher.update(combined.as_bytes());
+    let expected_hash = hex::encode(hasher.finalize());
 
-        return answer == expected_hash;
-    }
-    false
+    answer == expected_hash
 }
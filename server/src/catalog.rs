@@ -0,0 +1,186 @@
+use crate::storage;
+use rusqlite::{Connection, OptionalExtension, params};
+use shared::FileInfo;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// The catalog sits in the storage root but is hidden from scans like other dot-files
+pub const CATALOG_PATH: &str = "uploads/.catalog.db";
+
+#[derive(Error, Debug)]
+pub enum CatalogError {
+    #[error("Database Error: {0}")]
+    Db(#[from] rusqlite::Error),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Persistent record of every finished file: who uploaded it, when, its size and hash
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+impl Catalog {
+    /// Opens (or creates) the catalog database and its schema
+    pub fn open(path: &str) -> Result<Self, CatalogError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                file_name   TEXT PRIMARY KEY,
+                size        INTEGER NOT NULL,
+                file_hash   TEXT NOT NULL,
+                uploaded_by TEXT NOT NULL,
+                uploaded_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Catalog {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Inserts or replaces the entry for `info.file_name`
+    pub fn record(&self, info: &FileInfo) -> Result<(), CatalogError> {
+        self.lock().execute(
+            "INSERT OR REPLACE INTO files (file_name, size, file_hash, uploaded_by, uploaded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                info.file_name,
+                info.size as i64,
+                info.file_hash,
+                info.uploaded_by,
+                info.uploaded_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, file_name: &str) -> Result<(), CatalogError> {
+        self.lock()
+            .execute("DELETE FROM files WHERE file_name = ?1", params![file_name])?;
+        Ok(())
+    }
+
+    pub fn stat(&self, file_name: &str) -> Result<Option<FileInfo>, CatalogError> {
+        let info = self
+            .lock()
+            .query_row(
+                "SELECT file_name, size, file_hash, uploaded_by, uploaded_at
+                 FROM files WHERE file_name = ?1",
+                params![file_name],
+                row_to_info,
+            )
+            .optional()?;
+        Ok(info)
+    }
+
+    /// All catalogued files, ordered by name
+    pub fn list(&self) -> Result<Vec<FileInfo>, CatalogError> {
+        let conn = self.lock();
+        let mut statement = conn.prepare(
+            "SELECT file_name, size, file_hash, uploaded_by, uploaded_at
+             FROM files ORDER BY file_name",
+        )?;
+        let files = statement
+            .query_map([], row_to_info)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(files)
+    }
+
+    /// Re-derives the catalog from the files actually present in the storage root.
+    /// Entries whose content is unchanged keep their uploader and upload time.
+    /// Returns the number of files catalogued.
+    pub fn rebuild(&self) -> Result<usize, CatalogError> {
+        let mut found = Vec::new();
+        scan(Path::new("uploads"), "", true, &mut found)?;
+
+        let mut names = HashSet::new();
+        for file_name in &found {
+            let mut info = describe(file_name, "unknown")?;
+            if let Some(existing) = self.stat(file_name)?
+                && existing.file_hash == info.file_hash
+            {
+                info.uploaded_by = existing.uploaded_by;
+                info.uploaded_at = existing.uploaded_at;
+            }
+            self.record(&info)?;
+            names.insert(file_name.clone());
+        }
+
+        for stale in self.list()? {
+            if !names.contains(&stale.file_name) {
+                self.remove(&stale.file_name)?;
+            }
+        }
+        Ok(found.len())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Builds a catalog entry from the stored file on disk, hashing its content
+pub fn describe(file_name: &str, uploaded_by: &str) -> io::Result<FileInfo> {
+    let path = format!("uploads/{}", file_name);
+    let metadata = fs::metadata(&path)?;
+    let uploaded_at = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(FileInfo {
+        file_name: file_name.to_string(),
+        size: metadata.len(),
+        file_hash: storage::hash_file(Path::new(&path))?,
+        uploaded_by: uploaded_by.to_string(),
+        uploaded_at,
+    })
+}
+
+/// Seconds since the Unix epoch, as stored in `uploaded_at`
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Collects stored files below `dir`, skipping dot-files (chunk store, versions,
+/// partial merges, the catalog itself) and top-level staging directories
+fn scan(dir: &Path, prefix: &str, top_level: bool, found: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if top_level && Uuid::parse_str(&name).is_ok() {
+                continue;
+            }
+            scan(&entry.path(), &format!("{}/", relative), false, found)?;
+        } else if file_type.is_file() {
+            found.push(relative);
+        }
+    }
+    Ok(())
+}
+
+fn row_to_info(row: &rusqlite::Row) -> rusqlite::Result<FileInfo> {
+    Ok(FileInfo {
+        file_name: row.get(0)?,
+        size: row.get::<_, i64>(1)? as u64,
+        file_hash: row.get(2)?,
+        uploaded_by: row.get(3)?,
+        uploaded_at: row.get::<_, i64>(4)? as u64,
+    })
+}
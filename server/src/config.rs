@@ -0,0 +1,64 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// How received chunks are laid out on disk until the upload is finalized
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageMode {
+    /// Chunks go to the shared content-addressed store and are copied out on merge
+    Chunked,
+    /// Chunks are written in place into a preallocated file; merging is a rename
+    Direct,
+}
+
+/// What happens when a finished upload's name is already taken
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Refuse the upload
+    Reject,
+    /// Replace the existing file
+    Overwrite,
+    /// Store the upload as "name (1).ext", "name (2).ext", ...
+    Rename,
+    /// Replace the existing file but keep it as a restorable version
+    Version,
+}
+
+/// Chunk size used unless a file is large enough to need bigger chunks
+pub const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Files are split into at most roughly this many chunks before chunks grow
+const TARGET_MAX_CHUNKS: u64 = 10_000;
+
+/// Settings shared by every connection handler
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub storage_mode: StorageMode,
+    /// Smallest chunk size handed to clients
+    pub chunk_size: u64,
+    /// Upper bound on the chunk size, however large the file
+    pub max_chunk_size: u64,
+    pub collision_policy: CollisionPolicy,
+    /// Number of old versions retained per file under `CollisionPolicy::Version`
+    pub max_versions: usize,
+    /// Uploads whose names end in one of these are refused
+    pub forbidden_extensions: Vec<String>,
+    /// User names and the passwords they log in with
+    pub users: HashMap<String, String>,
+}
+
+impl Config {
+    /// Picks the chunk size for a file: the configured size, doubled until the file
+    /// fits in about `TARGET_MAX_CHUNKS` chunks, never above `max_chunk_size`
+    pub fn chunk_size_for(&self, total_size: u64) -> u64 {
+        let mut chunk_size = self.chunk_size;
+        while total_size.div_ceil(chunk_size) > TARGET_MAX_CHUNKS
+            && chunk_size * 2 <= self.max_chunk_size
+        {
+            chunk_size *= 2;
+        }
+        chunk_size
+    }
+}
@@ -0,0 +1,154 @@
+//! The server's TOML configuration file. Every table rejects keys it does not know,
+//! so typos are reported instead of silently ignored. See `paraflow.example.toml`
+//! for every setting.
+
+use crate::config::{CollisionPolicy, StorageMode};
+use serde::{Deserialize, Deserializer, de};
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::num::{NonZeroU16, NonZeroU64};
+use std::path::{Path, PathBuf};
+
+/// Settings read from the file; `None` where the file is silent, so command-line
+/// flags and built-in defaults can fill in
+#[derive(Debug, Default)]
+pub struct FileConfig {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub root: Option<PathBuf>,
+    pub storage_mode: Option<StorageMode>,
+    /// User names and their passwords; empty when the file defines none
+    pub users: HashMap<String, String>,
+    pub collision_policy: Option<CollisionPolicy>,
+    pub max_versions: Option<usize>,
+    pub forbidden_extensions: Option<Vec<String>>,
+    pub chunk_size: Option<u64>,
+    pub max_chunk_size: Option<u64>,
+    pub upload_ttl: Option<u64>,
+    pub gc_interval: Option<u64>,
+    pub shutdown_timeout: Option<u64>,
+    pub max_connections: Option<u64>,
+    pub max_queued_connections: Option<u64>,
+}
+
+/// The file as written, table by table
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Document {
+    listener: Listener,
+    storage: Storage,
+    auth: Auth,
+    policies: Policies,
+    limits: Limits,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Listener {
+    bind: Option<IpAddr>,
+    port: Option<NonZeroU16>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Storage {
+    #[serde(deserialize_with = "non_empty_path")]
+    root: Option<PathBuf>,
+    mode: Option<StorageMode>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Auth {
+    #[serde(deserialize_with = "passwords")]
+    users: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Policies {
+    collision: Option<CollisionPolicy>,
+    max_versions: Option<usize>,
+    #[serde(deserialize_with = "extensions")]
+    forbidden_extensions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    chunk_size: Option<NonZeroU64>,
+    max_chunk_size: Option<NonZeroU64>,
+    upload_ttl: Option<NonZeroU64>,
+    gc_interval: Option<NonZeroU64>,
+    shutdown_timeout: Option<u64>,
+    max_connections: Option<NonZeroU64>,
+    max_queued_connections: Option<u64>,
+}
+
+/// Reads and validates the file at `path`. Errors name the file and point at the
+/// line and setting at fault.
+pub fn load(path: &Path) -> Result<FileConfig, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let document: Document = toml::from_str(&text)
+        .map_err(|e| format!("{}: {}", path.display(), e.to_string().trim_end()))?;
+
+    let Document {
+        listener,
+        storage,
+        auth,
+        policies,
+        limits,
+    } = document;
+    Ok(FileConfig {
+        bind: listener.bind,
+        port: listener.port.map(NonZeroU16::get),
+        root: storage.root,
+        storage_mode: storage.mode,
+        users: auth.users,
+        collision_policy: policies.collision,
+        max_versions: policies.max_versions,
+        forbidden_extensions: policies.forbidden_extensions,
+        chunk_size: limits.chunk_size.map(NonZeroU64::get),
+        max_chunk_size: limits.max_chunk_size.map(NonZeroU64::get),
+        upload_ttl: limits.upload_ttl.map(NonZeroU64::get),
+        gc_interval: limits.gc_interval.map(NonZeroU64::get),
+        shutdown_timeout: limits.shutdown_timeout,
+        max_connections: limits.max_connections.map(NonZeroU64::get),
+        max_queued_connections: limits.max_queued_connections,
+    })
+}
+
+fn non_empty_path<'de, D: Deserializer<'de>>(d: D) -> Result<Option<PathBuf>, D::Error> {
+    let root = String::deserialize(d)?;
+    if root.is_empty() {
+        return Err(de::Error::custom("the storage root must not be empty"));
+    }
+    Ok(Some(PathBuf::from(root)))
+}
+
+fn passwords<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<String, String>, D::Error> {
+    let users = HashMap::<String, String>::deserialize(d)?;
+    match users.iter().find(|(_, password)| password.is_empty()) {
+        Some((user, _)) => Err(de::Error::custom(format!(
+            "the password of user `{}` must not be empty",
+            user
+        ))),
+        None => Ok(users),
+    }
+}
+
+fn extensions<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    let extensions = Vec::<String>::deserialize(d)?;
+    match extensions
+        .iter()
+        .find(|e| !e.starts_with('.') || e.len() < 2)
+    {
+        Some(bad) => Err(de::Error::custom(format!(
+            "extensions must start with a dot, like \".exe\", not \"{}\"",
+            bad
+        ))),
+        None => Ok(Some(extensions)),
+    }
+}
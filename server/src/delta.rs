@@ -0,0 +1,122 @@
+use crate::config::Config;
+use crate::storage::{self, MergeError, MergedFile};
+use sha2::{Digest, Sha256};
+use shared::delta::{self, BlockSignature};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// Buffer size used when copying ranges of the old file
+const COPY_BUFFER: u64 = 1024 * 1024;
+
+/// A delta sync in progress: the new file is rebuilt beside the old one from copy
+/// instructions and literal data, then swapped in for the old copy
+pub struct DeltaSession {
+    file_name: String,
+    /// Held open from the signature onwards, so copies read exactly what was signed
+    base: Option<File>,
+    base_len: u64,
+    part_path: PathBuf,
+    part: File,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl DeltaSession {
+    /// Opens the current copy of `file_name`, if any, and returns the session along
+    /// with the block size and signature the client should diff against
+    pub fn begin(file_name: &str) -> io::Result<(Self, u64, Vec<BlockSignature>)> {
+        let base = match File::open(format!("uploads/{}", file_name)) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let base_len = match &base {
+            Some(file) => file.metadata()?.len(),
+            None => 0,
+        };
+        let block_size = delta::block_size_for(base_len);
+        let blocks = match &base {
+            Some(file) => delta::signature(io::BufReader::new(file), block_size)?,
+            None => Vec::new(),
+        };
+
+        let part_path = storage::part_path(
+            &uuid::Uuid::new_v4().to_string(),
+            &format!("uploads/{}", file_name),
+        );
+        let part = File::create(&part_path)?;
+        let session = DeltaSession {
+            file_name: file_name.to_string(),
+            base,
+            base_len,
+            part_path,
+            part,
+            hasher: Sha256::new(),
+            size: 0,
+        };
+        Ok((session, block_size, blocks))
+    }
+
+    /// Appends `len` bytes of the old file starting at `offset`
+    pub fn copy(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let base = match &self.base {
+            Some(base) if offset.checked_add(len).is_some_and(|end| end <= self.base_len) => base,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Copy of {} bytes at {} is outside the old file", len, offset),
+                ));
+            }
+        };
+        let mut buffer = vec![0u8; COPY_BUFFER.min(len) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = COPY_BUFFER.min(len - done) as usize;
+            base.read_exact_at(&mut buffer[..n], offset + done)?;
+            self.part.write_all(&buffer[..n])?;
+            self.hasher.update(&buffer[..n]);
+            done += n as u64;
+        }
+        self.size += len;
+        Ok(())
+    }
+
+    /// Appends literal data sent by the client
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.part.write_all(data)?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Verifies the rebuilt file against the client's hash and moves it over the old one
+    pub fn finish(self, expected_hash: &str, config: &Config) -> Result<MergedFile, MergeError> {
+        let actual = hex::encode(self.hasher.clone().finalize());
+        if actual != expected_hash {
+            return Err(MergeError::HashMismatch {
+                expected: expected_hash.to_string(),
+                actual,
+            });
+        }
+        self.part.sync_all()?;
+        storage::replace(&self.part_path, &self.file_name, config)?;
+        println!(
+            ">> Delta sync complete. Saved {} bytes to uploads/{}",
+            self.size, self.file_name
+        );
+        Ok(MergedFile {
+            file_name: self.file_name.clone(),
+            file_hash: actual,
+            size: self.size,
+        })
+    }
+}
+
+impl Drop for DeltaSession {
+    /// An abandoned or failed sync leaves the old file untouched; only the part goes
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.part_path);
+    }
+}
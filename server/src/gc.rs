@@ -0,0 +1,133 @@
+use crate::storage::{self, CHUNK_STORE};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// What a garbage collection pass removed
+#[derive(Default, Debug)]
+pub struct GcReport {
+    pub expired_uploads: usize,
+    pub swept_chunks: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/// Deletes staging directories of uploads that have been idle for longer than `ttl`,
+/// then stored chunks no remaining upload refers to and that were not used within
+/// `ttl`, along with temporary chunk files orphaned by crashed writers.
+pub fn collect_garbage(ttl: Duration) -> io::Result<GcReport> {
+    let mut report = GcReport::default();
+    let entries = match fs::read_dir("uploads") {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        // Only staging directories are named by an upload id and carry a layout; user
+        // directories, finished files and the store are kept
+        let is_staging = storage::is_valid_upload_id(&entry.file_name().to_string_lossy())
+            && path.join("layout").is_file();
+        if !is_staging {
+            continue;
+        }
+        if idle_for(&last_activity(&path)?) < ttl {
+            continue;
+        }
+
+        let size = dir_size(&path)?;
+        fs::remove_dir_all(&path)?;
+        println!(
+            ">> GC: expired upload {} ({})",
+            entry.file_name().to_string_lossy(),
+            format_bytes(size)
+        );
+        report.expired_uploads += 1;
+        report.reclaimed_bytes += size;
+    }
+
+    let (swept_chunks, swept_bytes) = storage::sweep_chunk_store(ttl)?;
+    report.swept_chunks = swept_chunks;
+    report.reclaimed_bytes += swept_bytes;
+
+    if let Ok(entries) = fs::read_dir(CHUNK_STORE) {
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let is_temp = entry.file_name().to_string_lossy().ends_with(".tmp");
+            if is_temp && idle_for(&metadata.modified()?) >= ttl {
+                fs::remove_file(entry.path())?;
+                report.reclaimed_bytes += metadata.len();
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Runs `collect_garbage` every `interval` on a background thread
+pub fn spawn_janitor(ttl: Duration, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            match collect_garbage(ttl) {
+                Ok(report)
+                    if report.expired_uploads > 0
+                        || report.swept_chunks > 0
+                        || report.reclaimed_bytes > 0 =>
+                {
+                    print_report(&report)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("GC pass failed: {}", e),
+            }
+        }
+    });
+}
+
+pub fn print_report(report: &GcReport) {
+    println!(
+        "🧹 GC: expired {} uploads, swept {} unused chunks, reclaimed {}",
+        report.expired_uploads,
+        report.swept_chunks,
+        format_bytes(report.reclaimed_bytes)
+    );
+}
+
+/// Most recent modification of anything inside a staging directory
+fn last_activity(dir: &Path) -> io::Result<SystemTime> {
+    let mut latest = fs::metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        latest = latest.max(entry?.metadata()?.modified()?);
+    }
+    Ok(latest)
+}
+
+fn idle_for(time: &SystemTime) -> Duration {
+    SystemTime::now().duration_since(*time).unwrap_or_default()
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
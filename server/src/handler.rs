@@ -1,35 +1,79 @@
-use crate::{auth, storage};
-use sha2::{Digest, Sha256};
-use shared::{ENCRYPTION_KEY, Message, ParaFlowError, encryption, read_message, send_message};
-use std::io::Read;
+use crate::catalog::{self, Catalog};
+use crate::config::{CollisionPolicy, Config};
+use crate::delta::DeltaSession;
+use crate::shutdown::{POLL_INTERVAL, Shutdown};
+use crate::storage::{Layout, MergeError, MergedFile};
+use crate::{auth, storage, versions};
+use shared::compression::{self, Compression};
+use shared::delta::MAX_LITERAL_FRAME;
+use shared::{
+    ENCRYPTION_KEY, FileInfo, Message, ParaFlowError, encryption, hash_chunk, read_message,
+    send_message,
+};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
 
-pub fn handle_client(mut stream: TcpStream) -> Result<(), ParaFlowError> {
+/// Algorithms the server can decompress, in no particular order
+const SUPPORTED_COMPRESSION: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+pub fn handle_client(
+    mut stream: TcpStream,
+    config: &Config,
+    catalog: &Catalog,
+    shutdown: &Shutdown,
+) -> Result<(), ParaFlowError> {
+    let mut current_client = String::new();
     let mut current_salt = String::new();
     let mut is_authenticated = false;
+    let mut offered_compression = Vec::new();
+    let mut session_compression = Compression::None;
+    let mut delta_session: Option<DeltaSession> = None;
+    // The file this connection is downloading from, kept open between chunks
+    let mut download: Option<(String, File)> = None;
 
     loop {
-        // Read the next message; exit the loop if the connection closes
-        let request = match read_message(&mut stream) {
-            Ok(msg) => msg,
-            Err(_) => return Ok(()),
+        // Read the next message; exit the loop if the connection closes. A delta
+        // sync spans many messages, so it is allowed to finish during a shutdown.
+        let request = match next_request(&mut stream, shutdown, delta_session.is_some()) {
+            Some(Ok(msg)) => msg,
+            Some(Err(_)) => return Ok(()),
+            None => {
+                send_message(&mut stream, &Message::ShuttingDown)?;
+                return Ok(());
+            }
         };
 
         match request {
-            Message::LoginRequest { client_id } => {
+            Message::LoginRequest {
+                client_id,
+                compression,
+            } => {
                 println!("Login attempt: {}", client_id);
+                // A new login must be proven again, or the session could act as any user
+                is_authenticated = false;
+                current_client = client_id;
+                offered_compression = compression;
                 let salt = auth::generate_salt();
                 current_salt = salt.clone();
                 send_message(&mut stream, &Message::LoginChallenge { salt })?;
             }
             Message::LoginAnswer { hash } => {
-                if auth::verify_user("admin", &current_salt, &hash) {
+                if auth::verify_user(&config.users, &current_client, &current_salt, &hash) {
                     println!("Auth Success!");
                     is_authenticated = true;
+                    // Honour the client's preference order among what we support
+                    session_compression = offered_compression
+                        .iter()
+                        .copied()
+                        .find(|c| SUPPORTED_COMPRESSION.contains(c))
+                        .unwrap_or(Compression::None);
                     send_message(
                         &mut stream,
                         &Message::Welcome {
                             session_id: "s1".to_string(),
+                            compression: session_compression,
                         },
                     )?;
                 } else {
@@ -46,46 +90,157 @@ pub fn handle_client(mut stream: TcpStream) -> Result<(), ParaFlowError> {
                 return Err(ParaFlowError::SecurityError("Unauthorized Access".into()));
             }
 
-            Message::InitUpload { file_name, .. } => {
-                if file_name.ends_with(".sh") || file_name.ends_with(".exe") {
-                    send_message(
-                        &mut stream,
-                        &Message::ErrorMessage {
-                            text: "Forbidden file type".into(),
-                        },
-                    )?;
+            Message::InitUpload {
+                file_name,
+                total_size,
+            } => {
+                if let Err(text) = admit_upload(&file_name, config) {
+                    send_message(&mut stream, &Message::ErrorMessage { text })?;
+                    continue;
+                }
+                let uuid = uuid::Uuid::new_v4().to_string();
+                let chunk_size = config.chunk_size_for(total_size);
+                storage::create_upload_dir(
+                    &uuid,
+                    &file_name,
+                    &Layout {
+                        chunk_size,
+                        total_size,
+                    },
+                )?;
+                send_message(
+                    &mut stream,
+                    &Message::InitAck {
+                        chunk_size,
+                        upload_id: uuid,
+                    },
+                )?;
+            }
+            Message::InitStream { file_name } => {
+                if let Err(text) = admit_upload(&file_name, config) {
+                    send_message(&mut stream, &Message::ErrorMessage { text })?;
                     continue;
                 }
+                // The size is unknown, so the file cannot be sized up front
                 let uuid = uuid::Uuid::new_v4().to_string();
-                storage::create_upload_dir(&uuid)?;
+                storage::create_stream_dir(&uuid, &file_name, config.chunk_size)?;
                 send_message(
                     &mut stream,
                     &Message::InitAck {
-                        chunk_size: 0,
+                        chunk_size: config.chunk_size,
                         upload_id: uuid,
                     },
                 )?;
             }
+            Message::ChunkManifest {
+                upload_id,
+                chunk_hashes,
+            } => {
+                if !storage::is_valid_upload_id(&upload_id) {
+                    return Err(ParaFlowError::SecurityError("Invalid upload id".into()));
+                }
+                let layout = storage::read_layout(&upload_id)?;
+                let reply = if chunk_hashes.len() as u64 != layout.total_chunks() {
+                    Message::ErrorMessage {
+                        text: format!(
+                            "Expected {} chunk hashes, got {}",
+                            layout.total_chunks(),
+                            chunk_hashes.len()
+                        ),
+                    }
+                } else if !chunk_hashes.iter().all(|h| storage::is_valid_chunk_hash(h)) {
+                    Message::ErrorMessage {
+                        text: "Malformed chunk hash".into(),
+                    }
+                } else {
+                    match storage::write_manifest(&upload_id, &chunk_hashes, config.storage_mode) {
+                        Ok(known_chunks) => {
+                            println!(
+                                "Upload {}: {} of {} chunks already stored",
+                                upload_id,
+                                known_chunks.len(),
+                                chunk_hashes.len()
+                            );
+                            Message::ManifestAck { known_chunks }
+                        }
+                        Err(e) if e.kind() == ErrorKind::AlreadyExists => Message::ErrorMessage {
+                            text: format!("Upload {} already has a manifest", upload_id),
+                        },
+                        // Most likely the disk is full; the client cannot fix that by retrying
+                        Err(e) => {
+                            eprintln!("Preparing upload {} failed: {}", upload_id, e);
+                            Message::ErrorMessage {
+                                text: format!("Could not prepare upload {}: {}", upload_id, e),
+                            }
+                        }
+                    }
+                };
+                send_message(&mut stream, &reply)?;
+            }
             Message::ChunkMeta {
                 upload_id,
                 chunk_index,
                 size,
                 hash,
+                compression,
             } => {
+                if !storage::is_valid_upload_id(&upload_id) {
+                    return Err(ParaFlowError::SecurityError("Invalid upload id".into()));
+                }
+                let layout = storage::read_layout(&upload_id)?;
+                // Refuse to buffer anything larger than a full chunk could possibly be
+                if size > layout.chunk_size as usize + encryption::OVERHEAD {
+                    return Err(ParaFlowError::ProtocolError(format!(
+                        "Chunk of {} bytes exceeds the negotiated chunk size",
+                        size
+                    )));
+                }
+
                 let mut encrypted_data = vec![0u8; size];
                 stream.read_exact(&mut encrypted_data)?;
 
-                let mut hasher = Sha256::new();
-                hasher.update(&encrypted_data);
-                let server_hash = hex::encode(hasher.finalize());
+                if compression != Compression::None && compression != session_compression {
+                    return Err(ParaFlowError::ProtocolError(format!(
+                        "Chunk uses {:?} but {:?} was negotiated",
+                        compression, session_compression
+                    )));
+                }
+
+                let server_hash = hash_chunk(&encrypted_data);
 
                 if server_hash == hash {
-                    match encryption::decrypt_chunk(&encrypted_data, &ENCRYPTION_KEY) {
-                        Ok(decrypted_data) => {
-                            storage::save_chunk(&upload_id, chunk_index, &decrypted_data)?;
-                            send_message(&mut stream, &Message::ChunkAck { chunk_index })?;
+                    // A stream's chunks are only announced at completion, so any chunk that
+                    // fits is kept; otherwise it must match the manifest exactly
+                    let streaming = storage::is_stream(&upload_id);
+                    let (expected, max_len) = if streaming {
+                        (None, layout.chunk_size)
+                    } else {
+                        (
+                            storage::expected_chunk_hash(&upload_id, chunk_index)?,
+                            layout.chunk_len(chunk_index).unwrap_or(0),
+                        )
+                    };
+                    let decoded = decode_body(&encrypted_data, compression, max_len as usize);
+                    match decoded {
+                        Ok(chunk_data) if !chunk_data.is_empty() => {
+                            // The plaintext must match what was announced, or the store is poisoned
+                            let content_hash = hash_chunk(&chunk_data);
+                            let valid = streaming
+                                || (chunk_data.len() as u64 == max_len
+                                    && expected.as_deref() == Some(content_hash.as_str()));
+                            if valid {
+                                storage::save_chunk(
+                                    &upload_id,
+                                    chunk_index,
+                                    &content_hash,
+                                    &chunk_data,
+                                )?;
+                                send_message(&mut stream, &Message::ChunkAck { chunk_index })?;
+                            } else {
+                                send_message(&mut stream, &Message::ChunkNack { chunk_index })?;
+                            }
                         }
-                        Err(_) => send_message(&mut stream, &Message::ChunkNack { chunk_index })?,
+                        _ => send_message(&mut stream, &Message::ChunkNack { chunk_index })?,
                     }
                 } else {
                     send_message(&mut stream, &Message::ChunkNack { chunk_index })?;
@@ -95,10 +250,453 @@ pub fn handle_client(mut stream: TcpStream) -> Result<(), ParaFlowError> {
                 upload_id,
                 file_name,
                 total_chunks,
+                file_hash,
+            } => {
+                if !storage::is_valid_upload_id(&upload_id) {
+                    return Err(ParaFlowError::SecurityError("Invalid upload id".into()));
+                }
+                let reply = match check_completion_name(&upload_id, &file_name) {
+                    Ok(()) => {
+                        let merged = storage::merge_chunks(
+                            &upload_id,
+                            &file_name,
+                            total_chunks,
+                            &file_hash,
+                            config,
+                        );
+                        completion_reply(merged, &upload_id, &current_client, catalog)
+                    }
+                    Err(text) => Message::ErrorMessage { text },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::CompleteStream {
+                upload_id,
+                file_name,
+                total_size,
+                chunk_hashes,
+                file_hash,
+            } => {
+                if !storage::is_valid_upload_id(&upload_id) {
+                    return Err(ParaFlowError::SecurityError("Invalid upload id".into()));
+                }
+                let layout = storage::read_layout(&upload_id)?;
+                let expected_chunks = total_size.div_ceil(layout.chunk_size);
+                let reply = if !storage::is_stream(&upload_id) {
+                    Message::ErrorMessage {
+                        text: "Upload was not started as a stream".into(),
+                    }
+                } else if let Err(text) = check_completion_name(&upload_id, &file_name) {
+                    Message::ErrorMessage { text }
+                } else if chunk_hashes.len() as u64 != expected_chunks {
+                    Message::ErrorMessage {
+                        text: format!(
+                            "{} bytes need {} chunks, got {} hashes",
+                            total_size,
+                            expected_chunks,
+                            chunk_hashes.len()
+                        ),
+                    }
+                } else if !chunk_hashes.iter().all(|h| storage::is_valid_chunk_hash(h)) {
+                    Message::ErrorMessage {
+                        text: "Malformed chunk hash".into(),
+                    }
+                } else {
+                    storage::seal_stream(&upload_id, total_size, &chunk_hashes)?;
+                    let merged = storage::merge_chunks(
+                        &upload_id,
+                        &file_name,
+                        expected_chunks,
+                        &file_hash,
+                        config,
+                    );
+                    completion_reply(merged, &upload_id, &current_client, catalog)
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::SyncRequest { file_name } => {
+                if let Err(text) = admit_target(&file_name, config) {
+                    send_message(&mut stream, &Message::ErrorMessage { text })?;
+                    continue;
+                }
+                let (session, block_size, blocks) = DeltaSession::begin(&file_name)?;
+                println!(
+                    "Delta sync of {}: sent {} block signatures",
+                    file_name,
+                    blocks.len()
+                );
+                delta_session = Some(session);
+                send_message(&mut stream, &Message::Signature { block_size, blocks })?;
+            }
+            Message::DeltaCopy { offset, len } => {
+                let session = delta_session.as_mut().ok_or_else(|| {
+                    ParaFlowError::ProtocolError("Delta without a sync request".into())
+                })?;
+                session.copy(offset, len)?;
+            }
+            Message::DeltaData {
+                size,
+                hash,
+                compression,
             } => {
-                storage::merge_chunks(&upload_id, &file_name, total_chunks)?;
+                let session = delta_session.as_mut().ok_or_else(|| {
+                    ParaFlowError::ProtocolError("Delta without a sync request".into())
+                })?;
+                if size > MAX_LITERAL_FRAME + encryption::OVERHEAD {
+                    return Err(ParaFlowError::ProtocolError(format!(
+                        "Delta frame of {} bytes is too large",
+                        size
+                    )));
+                }
+                let mut encrypted_data = vec![0u8; size];
+                stream.read_exact(&mut encrypted_data)?;
+
+                if compression != Compression::None && compression != session_compression {
+                    return Err(ParaFlowError::ProtocolError(format!(
+                        "Delta uses {:?} but {:?} was negotiated",
+                        compression, session_compression
+                    )));
+                }
+                // There is nothing to retry against, so a damaged frame ends the sync
+                if hash_chunk(&encrypted_data) != hash {
+                    return Err(ParaFlowError::SecurityError(
+                        "Delta frame failed its integrity check".into(),
+                    ));
+                }
+                let data = decode_body(&encrypted_data, compression, MAX_LITERAL_FRAME)
+                    .map_err(ParaFlowError::EncryptionError)?;
+                session.write(&data)?;
+            }
+            Message::DeltaComplete { file_hash } => {
+                let session = delta_session.take().ok_or_else(|| {
+                    ParaFlowError::ProtocolError("Delta without a sync request".into())
+                })?;
+                let merged = session.finish(&file_hash, config);
+                let reply = completion_reply(merged, "delta sync", &current_client, catalog);
+                send_message(&mut stream, &reply)?;
+            }
+            Message::DownloadRequest { file_name } => {
+                let reply = match describe_download(&file_name, config, catalog) {
+                    Ok(reply) => reply,
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Download failed: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::HashRequest {
+                file_name,
+                chunk_size,
+            } => {
+                let out_of_range = |&size: &u64| size == 0 || size > config.max_chunk_size;
+                if let Some(size) = chunk_size.filter(out_of_range) {
+                    let text = format!(
+                        "Verify failed: chunk size {} is not between 1 and {}",
+                        size, config.max_chunk_size
+                    );
+                    send_message(&mut stream, &Message::ErrorMessage { text })?;
+                    continue;
+                }
+                let reply = match storage::hash_stored(&file_name, chunk_size) {
+                    Ok((size, file_hash, chunk_hashes)) => {
+                        println!("Hashed {} ({} bytes) for verification", file_name, size);
+                        Message::HashReport {
+                            file_name,
+                            size,
+                            file_hash,
+                            chunk_hashes,
+                        }
+                    }
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Verify failed: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::FetchChunk {
+                file_name,
+                chunk_index,
+                chunk_size,
+            } => {
+                if chunk_size == 0 || chunk_size > config.max_chunk_size {
+                    return Err(ParaFlowError::ProtocolError(format!(
+                        "Chunk size {} is out of range",
+                        chunk_size
+                    )));
+                }
+                if download.as_ref().is_none_or(|(name, _)| *name != file_name) {
+                    match storage::open_stored(&file_name) {
+                        Ok(file) => download = Some((file_name.clone(), file)),
+                        Err(e) => {
+                            let text = format!("Download failed: {}", e);
+                            send_message(&mut stream, &Message::ErrorMessage { text })?;
+                            continue;
+                        }
+                    }
+                }
+                let (_, file) = download.as_ref().expect("opened above");
+                let data = read_at_most(file, chunk_index.saturating_mul(chunk_size), chunk_size)?;
+                if data.is_empty() && chunk_index > 0 {
+                    let text = format!("Chunk #{} is past the end of {}", chunk_index, file_name);
+                    send_message(&mut stream, &Message::ErrorMessage { text })?;
+                    continue;
+                }
+                let (compression, payload) =
+                    compression::compress_chunk(&data, session_compression);
+                let encrypted = encryption::encrypt_chunk(&payload, &ENCRYPTION_KEY)
+                    .map_err(ParaFlowError::EncryptionError)?;
+                send_message(
+                    &mut stream,
+                    &Message::ChunkData {
+                        chunk_index,
+                        size: encrypted.len(),
+                        hash: hash_chunk(&encrypted),
+                        compression,
+                    },
+                )?;
+                stream.write_all(&encrypted)?;
+            }
+            Message::ListVersions { file_name } => {
+                let listed =
+                    storage::check_file_name(&file_name).and_then(|()| versions::list(&file_name));
+                let reply = match listed {
+                    Ok(versions) => Message::VersionList { versions },
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Could not list versions: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::RestoreVersion {
+                file_name,
+                version_id,
+            } => {
+                let reply = if !versions::is_valid_version_id(&version_id) {
+                    Message::ErrorMessage {
+                        text: "Malformed version id".into(),
+                    }
+                } else {
+                    match storage::restore_version(&file_name, &version_id, config) {
+                        Ok(()) => {
+                            let recorded = catalog::describe(&file_name, &current_client)
+                                .map_err(Into::into)
+                                .and_then(|info| catalog.record(&info));
+                            if let Err(e) = recorded {
+                                eprintln!("Catalog update for {} failed: {}", file_name, e);
+                            }
+                            Message::RestoreAck {
+                                file_name,
+                                version_id,
+                            }
+                        }
+                        Err(e) => Message::ErrorMessage {
+                            text: format!("Restore failed: {}", e),
+                        },
+                    }
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::ListFiles => {
+                let reply = match catalog.list() {
+                    Ok(files) => Message::FileList { files },
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Could not list files: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::StatFile { file_name } => {
+                let reply = match catalog.stat(&file_name) {
+                    Ok(Some(info)) => Message::FileStat { info },
+                    Ok(None) => Message::ErrorMessage {
+                        text: format!("No such file: {}", file_name),
+                    },
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Could not stat file: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
+            }
+            Message::DeleteFile { file_name } => {
+                let reply = match storage::delete_file(&file_name) {
+                    Ok(()) => {
+                        if let Err(e) = catalog.remove(&file_name) {
+                            eprintln!("Catalog update for {} failed: {}", file_name, e);
+                        }
+                        Message::DeleteAck { file_name }
+                    }
+                    Err(e) => Message::ErrorMessage {
+                        text: format!("Delete failed: {}", e),
+                    },
+                };
+                send_message(&mut stream, &reply)?;
             }
             _ => {}
         }
     }
 }
+
+/// Waits for the next request, looking out for a shutdown while the connection is
+/// idle. Returns `None` when the server is shutting down and no request has started
+/// to arrive; a request already on its way is read and served first.
+fn next_request(
+    stream: &mut TcpStream,
+    shutdown: &Shutdown,
+    finish_first: bool,
+) -> Option<Result<Message, ParaFlowError>> {
+    if let Err(e) = stream.set_read_timeout(Some(POLL_INTERVAL)) {
+        return Some(Err(e.into()));
+    }
+    let mut probe = [0u8; 1];
+    loop {
+        match stream.peek(&mut probe) {
+            Ok(_) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shutdown.is_stopping() && !finish_first {
+                    return None;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+    }
+    // A closed connection is reported by `read_message` below
+    Some(
+        stream
+            .set_read_timeout(None)
+            .map_err(ParaFlowError::from)
+            .and_then(|()| read_message(stream)),
+    )
+}
+
+/// Checks that an upload of `file_name` may start and creates the directories it
+/// will be stored in; the error is the reason to turn it away
+fn admit_upload(file_name: &str, config: &Config) -> Result<(), String> {
+    admit_target(file_name, config)?;
+    // Fail fast; the policy is enforced again at merge time for concurrent uploads
+    if config.collision_policy == CollisionPolicy::Reject && storage::file_exists(file_name) {
+        return Err("File already exists".into());
+    }
+    Ok(())
+}
+
+/// Checks that `file_name` may be written at all and creates the directories it will
+/// be stored in. A sync stops here: it replaces its target whatever the collision
+/// policy, so only uploads go on to `admit_upload`'s policy check.
+fn admit_target(file_name: &str, config: &Config) -> Result<(), String> {
+    storage::check_file_name(file_name).map_err(|e| e.to_string())?;
+    if config
+        .forbidden_extensions
+        .iter()
+        .any(|extension| file_name.ends_with(extension.as_str()))
+    {
+        return Err("Forbidden file type".into());
+    }
+    if std::path::Path::new("uploads").join(file_name).is_dir() {
+        return Err(format!("{} is a directory", file_name));
+    }
+    storage::create_parent_dirs(file_name)
+        .map_err(|e| format!("Could not create the directories for {}: {}", file_name, e))
+}
+
+/// Checks that a completion names the file its upload was admitted as, so the name
+/// checks made at init cannot be sidestepped at merge time
+fn check_completion_name(upload_id: &str, file_name: &str) -> Result<(), String> {
+    match storage::admitted_name(upload_id) {
+        Ok(admitted) if admitted == file_name => Ok(()),
+        Ok(admitted) => Err(format!(
+            "Upload {} was started as {}, not {}",
+            upload_id, admitted, file_name
+        )),
+        Err(e) => Err(format!("Upload {} is unknown: {}", upload_id, e)),
+    }
+}
+
+/// Answers a download request with the file's size, hash and the chunk size to fetch by.
+/// The catalog's hash is trusted while the size still matches; otherwise it is recomputed.
+fn describe_download(
+    file_name: &str,
+    config: &Config,
+    catalog: &Catalog,
+) -> std::io::Result<Message> {
+    let file = storage::open_stored(file_name)?;
+    let size = file.metadata()?.len();
+    let file_hash = match catalog.stat(file_name) {
+        Ok(Some(info)) if info.size == size => info.file_hash,
+        _ => storage::hash_file(&std::path::Path::new("uploads").join(file_name))?,
+    };
+    println!("Download of {} ({} bytes) requested", file_name, size);
+    Ok(Message::DownloadInfo {
+        file_name: file_name.to_string(),
+        size,
+        file_hash,
+        chunk_size: config.chunk_size_for(size),
+    })
+}
+
+/// Reads up to `len` bytes at `offset`, fewer only at the end of the file
+fn read_at_most(file: &File, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read_at(&mut buffer[filled..], offset + filled as u64)? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    buffer.truncate(filled);
+    Ok(buffer)
+}
+
+/// Decrypts a received body and undoes its compression, refusing to inflate past `max_len`
+fn decode_body(
+    encrypted_data: &[u8],
+    compression: Compression,
+    max_len: usize,
+) -> Result<Vec<u8>, String> {
+    encryption::decrypt_chunk(encrypted_data, &ENCRYPTION_KEY)
+        .and_then(|plain| compression::decompress_chunk(&plain, compression, max_len))
+}
+
+/// Records a finished upload in the catalog and builds the reply to its completion
+/// request; `context` identifies the upload in the server log if it failed
+fn completion_reply(
+    merged: Result<MergedFile, MergeError>,
+    context: &str,
+    uploaded_by: &str,
+    catalog: &Catalog,
+) -> Message {
+    match merged {
+        Ok(MergedFile {
+            file_name,
+            file_hash,
+            size,
+        }) => {
+            let info = FileInfo {
+                file_name: file_name.clone(),
+                size,
+                file_hash: file_hash.clone(),
+                uploaded_by: uploaded_by.to_string(),
+                uploaded_at: catalog::now(),
+            };
+            if let Err(e) = catalog.record(&info) {
+                eprintln!("Catalog update for {} failed: {}", file_name, e);
+            }
+            Message::CompleteAck {
+                file_name,
+                file_hash,
+            }
+        }
+        Err(MergeError::MissingChunks(missing_chunks)) => Message::CompleteFailed {
+            reason: format!("{} chunks were never received", missing_chunks.len()),
+            missing_chunks,
+        },
+        Err(e) => {
+            eprintln!("Merge of {} failed: {}", context, e);
+            Message::CompleteFailed {
+                missing_chunks: Vec::new(),
+                reason: e.to_string(),
+            }
+        }
+    }
+}
@@ -1,33 +1,292 @@
 mod auth;
+mod catalog;
+mod config;
+mod config_file;
+mod delta;
+mod gc;
 mod handler;
+mod pool;
+mod shutdown;
 mod storage;
+mod versions;
 
-use clap::Parser;
-use dotenvy;
-use std::net::TcpListener;
+use catalog::{CATALOG_PATH, Catalog};
+use clap::{Parser, Subcommand};
+use config::{CollisionPolicy, Config, DEFAULT_CHUNK_SIZE, StorageMode};
+use config_file::FileConfig;
+use pool::{BUSY_RETRY_AFTER, ConnectionPool};
+use shared::{FileInfo, Message};
+use shutdown::{POLL_INTERVAL, Shutdown};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
+/// Command-line flags override the config file, which overrides the defaults shown
 #[derive(Parser)]
 struct Cli {
-    #[arg(short, long, default_value_t = 7878)]
-    port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML config file; see paraflow.example.toml for the schema
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// Port to listen on [default: 7878]
+    #[arg(short, long, value_parser = clap::value_parser!(u16).range(1..))]
+    port: Option<u16>,
+    /// Directory holding the stored files, staging data and catalog [default: .]
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
+    /// Where chunks are written while an upload is in progress [default: chunked]
+    #[arg(long, value_enum)]
+    storage_mode: Option<StorageMode>,
+    /// Chunk size in bytes handed to clients; grows for very large files [default: 4194304]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_size: Option<u64>,
+    /// Largest chunk size in bytes the server will ever hand out [default: 67108864]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_chunk_size: Option<u64>,
+    /// What to do when an upload's file name is already taken [default: version]
+    #[arg(long, value_enum)]
+    collision_policy: Option<CollisionPolicy>,
+    /// Old versions retained per file when the collision policy is `version` [default: 10]
+    #[arg(long)]
+    max_versions: Option<usize>,
+    /// Seconds an incomplete upload may sit idle before its staging data is deleted, and
+    /// an unreferenced stored chunk is kept for deduplication [default: 86400]
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    upload_ttl: Option<u64>,
+    /// Seconds between background garbage collection passes [default: 600]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    gc_interval: Option<u64>,
+    /// Seconds open connections get to finish on SIGTERM or SIGINT [default: 30]
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+    /// Connections served at once, each by its own thread [default: 64]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: Option<u64>,
+    /// Connections that may wait for a free slot before new ones are told to come
+    /// back later [default: 16]
+    #[arg(long)]
+    max_queued_connections: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Delete expired uploads and unused stored chunks once and exit
+    Gc,
+    /// Rebuild the metadata catalog by scanning the storage root, then exit
+    RebuildCatalog,
 }
 
 fn main() {
     dotenvy::dotenv().ok();
     let args = Cli::parse();
-    let addr = format!("0.0.0.0:{}", args.port);
-    let listener = TcpListener::bind(&addr).expect("Could not bind to port");
+    let file = match &args.config {
+        Some(path) => config_file::load(path).unwrap_or_else(|e| invalid_config(&e)),
+        None => FileConfig::default(),
+    };
+
+    let root = args
+        .root
+        .or(file.root)
+        .unwrap_or_else(|| PathBuf::from("."));
+    if let Err(e) = std::fs::create_dir_all(&root).and_then(|_| std::env::set_current_dir(&root)) {
+        invalid_config(&format!(
+            "Storage root {} is not usable: {}",
+            root.display(),
+            e
+        ));
+    }
+
+    let upload_ttl =
+        Duration::from_secs(args.upload_ttl.or(file.upload_ttl).unwrap_or(24 * 60 * 60));
+
+    if let Some(Command::Gc) = args.command {
+        match gc::collect_garbage(upload_ttl) {
+            Ok(report) => gc::print_report(&report),
+            Err(e) => {
+                eprintln!("GC failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    println!("🌍 Server listening on {} ...", addr);
+    let chunk_size = args
+        .chunk_size
+        .or(file.chunk_size)
+        .unwrap_or(DEFAULT_CHUNK_SIZE);
+    let max_chunk_size = match args.max_chunk_size.or(file.max_chunk_size) {
+        Some(max) if max < chunk_size => invalid_config(&format!(
+            "The max chunk size ({}) is smaller than the chunk size ({})",
+            max, chunk_size
+        )),
+        Some(max) => max,
+        None => chunk_size.max(64 * 1024 * 1024),
+    };
+    let config = Arc::new(Config {
+        storage_mode: args
+            .storage_mode
+            .or(file.storage_mode)
+            .unwrap_or(StorageMode::Chunked),
+        chunk_size,
+        max_chunk_size,
+        collision_policy: args
+            .collision_policy
+            .or(file.collision_policy)
+            .unwrap_or(CollisionPolicy::Version),
+        max_versions: args.max_versions.or(file.max_versions).unwrap_or(10),
+        forbidden_extensions: file
+            .forbidden_extensions
+            .unwrap_or_else(|| vec![".sh".into(), ".exe".into()]),
+        users: if file.users.is_empty() {
+            auth::default_users()
+        } else {
+            file.users
+        },
+    });
 
-    for stream in listener.incoming() {
-        if let Ok(s) = stream {
-            thread::spawn(|| {
-                if let Err(e) = handler::handle_client(s) {
-                    eprintln!("Connection error: {}", e);
+    std::fs::create_dir_all("uploads").expect("Could not create storage root");
+    let fresh_catalog = !std::path::Path::new(CATALOG_PATH).exists();
+    let catalog = Arc::new(Catalog::open(CATALOG_PATH).expect("Could not open catalog"));
+    let rebuild_only = matches!(args.command, Some(Command::RebuildCatalog));
+    if fresh_catalog || rebuild_only {
+        match catalog.rebuild() {
+            Ok(n) => println!("📇 Catalog rebuilt: {} files", n),
+            Err(e) => {
+                eprintln!("Catalog rebuild failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if rebuild_only {
+            return;
+        }
+    }
+
+    match storage::recover_interrupted_merges(&config) {
+        Ok(recovered) if recovered.is_empty() => {}
+        Ok(recovered) => {
+            println!("♻️  Recovered {} interrupted merges", recovered.len());
+            for merged in recovered {
+                let info = FileInfo {
+                    file_name: merged.file_name,
+                    size: merged.size,
+                    file_hash: merged.file_hash,
+                    uploaded_by: "unknown".into(),
+                    uploaded_at: catalog::now(),
+                };
+                if let Err(e) = catalog.record(&info) {
+                    eprintln!("Catalog update for {} failed: {}", info.file_name, e);
                 }
-            });
+            }
         }
+        Err(e) => eprintln!("Merge recovery failed: {}", e),
     }
+
+    let gc_interval = args.gc_interval.or(file.gc_interval).unwrap_or(10 * 60);
+    gc::spawn_janitor(upload_ttl, Duration::from_secs(gc_interval));
+
+    let addr = SocketAddr::new(
+        args.bind
+            .or(file.bind)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        args.port.or(file.port).unwrap_or(7878),
+    );
+    let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+        eprintln!("Could not listen on {}: {}", addr, e);
+        std::process::exit(1);
+    });
+
+    let shutdown_timeout = Duration::from_secs(
+        args.shutdown_timeout
+            .or(file.shutdown_timeout)
+            .unwrap_or(30),
+    );
+    let shutdown = Arc::new(Shutdown::default());
+    let signalled = Arc::clone(&shutdown);
+    let handler = ctrlc::set_handler(move || {
+        if !signalled.begin() {
+            eprintln!("Second signal, exiting without waiting for connections");
+            std::process::exit(1);
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!("Could not install the signal handler: {}", e);
+        std::process::exit(1);
+    }
+    // Accept without blocking so a shutdown is noticed between connections
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Could not listen on {}: {}", addr, e);
+        std::process::exit(1);
+    }
+
+    let max_connections = args.max_connections.or(file.max_connections).unwrap_or(64);
+    let max_queued = args
+        .max_queued_connections
+        .or(file.max_queued_connections)
+        .unwrap_or(16);
+    let pool = {
+        let shutdown = Arc::clone(&shutdown);
+        ConnectionPool::start(max_connections as usize, max_queued as usize, move |s| {
+            if let Err(e) = handler::handle_client(s, &config, &catalog, &shutdown) {
+                eprintln!("Connection error: {}", e);
+            }
+        })
+    };
+
+    println!(
+        "🌍 Server listening on {} ({} connections at once) ...",
+        addr, max_connections
+    );
+
+    while !shutdown.is_stopping() {
+        let s = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        if let Err(e) = s.set_nonblocking(false) {
+            eprintln!("Connection error: {}", e);
+            continue;
+        }
+        if let Err(s) = pool.submit(s, shutdown.track()) {
+            let retry_after_ms = BUSY_RETRY_AFTER.as_millis() as u64;
+            pool::turn_away(s, Message::Busy { retry_after_ms });
+        }
+    }
+    drop(listener);
+    // Waited for, so the replies go out even if nothing else is left to drain
+    let turned_away: Vec<_> = pool
+        .take_queued()
+        .into_iter()
+        .map(|s| pool::turn_away(s, Message::ShuttingDown))
+        .collect();
+    for handle in turned_away {
+        let _ = handle.join();
+    }
+
+    println!(
+        "🛑 Shutting down: no longer accepting connections, giving open ones {}s to finish",
+        shutdown_timeout.as_secs()
+    );
+    let left = shutdown.drain(shutdown_timeout);
+    if left > 0 {
+        eprintln!("Exiting with {} connections still open", left);
+        std::process::exit(1);
+    }
+    println!("👋 All connections closed");
+}
+
+/// Reports a configuration problem and exits before anything is served
+fn invalid_config(message: &str) -> ! {
+    eprintln!("Invalid configuration: {}", message);
+    std::process::exit(2);
 }
@@ -0,0 +1,104 @@
+use crate::shutdown::{ConnectionGuard, POLL_INTERVAL};
+use shared::{Message, read_message, send_message};
+use std::collections::VecDeque;
+use std::net::{self, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client turned away with `Busy` is asked to wait before trying again
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// A connection accepted but not yet picked up by a worker
+type Pending = (TcpStream, ConnectionGuard);
+
+struct State {
+    pending: VecDeque<Pending>,
+    /// Workers waiting for a connection
+    idle: usize,
+}
+
+/// A fixed set of threads serving connections, with a bounded queue in front of them
+pub struct ConnectionPool {
+    state: Mutex<State>,
+    queued: Condvar,
+    /// Connections that may wait for a busy worker; more are turned away
+    queue_limit: usize,
+}
+
+impl ConnectionPool {
+    /// Starts `workers` threads that each run `serve` on one connection at a time
+    pub fn start<F>(workers: usize, queue_limit: usize, serve: F) -> Arc<Self>
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let pool = Arc::new(ConnectionPool {
+            state: Mutex::new(State {
+                pending: VecDeque::new(),
+                idle: 0,
+            }),
+            queued: Condvar::new(),
+            queue_limit,
+        });
+        let serve = Arc::new(serve);
+        for _ in 0..workers {
+            let pool = Arc::clone(&pool);
+            let serve = Arc::clone(&serve);
+            thread::spawn(move || {
+                loop {
+                    let (stream, guard) = pool.next();
+                    serve(stream);
+                    drop(guard);
+                }
+            });
+        }
+        pool
+    }
+
+    /// Hands a connection to an idle worker or queues it for the next one to
+    /// finish. Gives the connection back when the queue is full.
+    pub fn submit(&self, stream: TcpStream, guard: ConnectionGuard) -> Result<(), TcpStream> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.len() >= state.idle + self.queue_limit {
+            return Err(stream);
+        }
+        state.pending.push_back((stream, guard));
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// Removes every connection still waiting for a worker
+    pub fn take_queued(&self) -> Vec<TcpStream> {
+        let mut state = self.state.lock().unwrap();
+        state.pending.drain(..).map(|(stream, _)| stream).collect()
+    }
+
+    fn next(&self) -> Pending {
+        let mut state = self.state.lock().unwrap();
+        state.idle += 1;
+        loop {
+            if let Some(pending) = state.pending.pop_front() {
+                state.idle -= 1;
+                return pending;
+            }
+            state = self.queued.wait(state).unwrap();
+        }
+    }
+}
+
+/// Sends `reply` and closes the connection on a thread of its own, so the accept
+/// loop is never held up by the client. The login request the client has most
+/// likely sent already is read first, so closing does not reset the connection
+/// before the client has seen the reply. Timeouts bound how long a client that
+/// stops reading can keep the thread around.
+pub fn turn_away(mut stream: TcpStream, reply: Message) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let _ = stream.set_write_timeout(Some(POLL_INTERVAL));
+        if send_message(&mut stream, &reply).is_err() {
+            return;
+        }
+        let _ = stream.shutdown(net::Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(POLL_INTERVAL));
+        let _ = read_message(&mut stream);
+    })
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the accept loop and idle connections look for a shutdown
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shutdown state shared by the accept loop, every connection and the signal handler
+#[derive(Default)]
+pub struct Shutdown {
+    stopping: AtomicBool,
+    /// Connections still being served
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Shutdown {
+    /// Starts the shutdown; returns false if it had already started
+    pub fn begin(&self) -> bool {
+        !self.stopping.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Counts a connection as open until the returned guard is dropped
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        *self.open.lock().unwrap() += 1;
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    /// Waits until every connection has closed or `timeout` has passed, and
+    /// returns how many are still open
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut open = self.open.lock().unwrap();
+        while *open > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            open = self.closed.wait_timeout(open, left).unwrap().0;
+        }
+        *open
+    }
+}
+
+/// Keeps a connection counted by `Shutdown::drain` while it is alive
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+        self.0.closed.notify_all();
+    }
+}
@@ -1,17 +1,290 @@
-use std::fs::{self, File};
-use std::io::{self};
+use crate::config::{CollisionPolicy, Config, StorageMode};
+use crate::versions;
+use sha2::{Digest, Sha256};
+use shared::hash_chunk;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
 
-pub fn create_upload_dir(upload_id: &str) -> io::Result<()> {
+/// Content-addressed store shared by all uploads; chunks are named by their SHA-256
+pub const CHUNK_STORE: &str = "uploads/.chunks";
+
+/// Serializes the "is the name taken?" check with the rename that claims the name,
+/// so concurrent uploads of the same file cannot silently overwrite each other
+static FINALIZE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Serializes claiming a stored chunk with the sweep that deletes unreferenced ones,
+/// so a chunk an upload has just counted on is never swept from under it
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// How an upload is sliced, fixed by the server when the upload is created
+pub struct Layout {
+    pub chunk_size: u64,
+    pub total_size: u64,
+}
+
+impl Layout {
+    pub fn total_chunks(&self) -> u64 {
+        self.total_size.div_ceil(self.chunk_size)
+    }
+
+    /// Exact plaintext length of `chunk_index`; only the last chunk may be short
+    pub fn chunk_len(&self, chunk_index: u64) -> Option<u64> {
+        if chunk_index >= self.total_chunks() {
+            return None;
+        }
+        let start = chunk_index * self.chunk_size;
+        Some(self.chunk_size.min(self.total_size - start))
+    }
+}
+
+/// Where and as what a merged upload ended up
+pub struct MergedFile {
+    pub file_name: String,
+    pub file_hash: String,
+    pub size: u64,
+}
+
+/// Reasons a merge can be refused; the client gets these back in `CompleteFailed`
+#[derive(Error, Debug)]
+pub enum MergeError {
+    #[error("{} chunks were never received", .0.len())]
+    MissingChunks(Vec<u64>),
+
+    #[error("File hash mismatch: expected {expected}, assembled {actual}")]
+    HashMismatch { expected: String, actual: String },
+
+    #[error("File already exists: {0}")]
+    NameTaken(String),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A chunk hash is used as a file name, so only accept a well-formed SHA-256 hex digest
+pub fn is_valid_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Creates the staging directory and records the name the upload was admitted under
+/// and how it is sliced
+pub fn create_upload_dir(upload_id: &str, file_name: &str, layout: &Layout) -> io::Result<()> {
     let path = format!("uploads/{}", upload_id);
-    fs::create_dir_all(path)
+    fs::create_dir_all(&path)?;
+    fs::write(format!("{}/name", path), file_name)?;
+    write_layout(upload_id, layout)
 }
 
-pub fn save_chunk(upload_id: &str, chunk_index: u64, data: &[u8]) -> io::Result<()> {
-    let path = format!("uploads/{}/chunk_{}", upload_id, chunk_index);
-    fs::write(path, data)
+/// Creates the staging directory for an upload of unknown length. Its chunks always
+/// go to the chunk store; the layout and manifest are completed by `seal_stream`.
+pub fn create_stream_dir(upload_id: &str, file_name: &str, chunk_size: u64) -> io::Result<()> {
+    create_upload_dir(
+        upload_id,
+        file_name,
+        &Layout {
+            chunk_size,
+            total_size: 0,
+        },
+    )?;
+    fs::create_dir_all(CHUNK_STORE)?;
+    File::create(format!("uploads/{}/stream", upload_id))?;
+    Ok(())
 }
 
-pub fn merge_chunks(upload_id: &str, file_name: &str, total_chunks: u64) -> io::Result<()> {
+/// True while an upload started with `create_stream_dir` is still receiving chunks
+pub fn is_stream(upload_id: &str) -> bool {
+    Path::new(&format!("uploads/{}/stream", upload_id)).exists()
+}
+
+/// Records the final size and chunk list of a stream, turning it into an ordinary
+/// chunked upload that `merge_chunks` can finalize
+pub fn seal_stream(upload_id: &str, total_size: u64, chunk_hashes: &[String]) -> io::Result<()> {
+    let layout = read_layout(upload_id)?;
+    write_layout(
+        upload_id,
+        &Layout {
+            chunk_size: layout.chunk_size,
+            total_size,
+        },
+    )?;
+    fs::write(
+        format!("uploads/{}/manifest", upload_id),
+        chunk_hashes.join("\n"),
+    )?;
+    fs::remove_file(format!("uploads/{}/stream", upload_id))
+}
+
+/// Records which chunk hash belongs at each index and returns the indices whose
+/// content is already stored. In direct mode the target file is preallocated and
+/// those known chunks are copied into place straight away. An upload has exactly one
+/// manifest; a second one fails with `AlreadyExists` rather than discarding chunks
+/// already written. If the upload cannot be prepared, nothing is left behind, so the
+/// manifest can be sent again.
+pub fn write_manifest(
+    upload_id: &str,
+    chunk_hashes: &[String],
+    mode: StorageMode,
+) -> io::Result<Vec<u64>> {
+    let path = format!("uploads/{}", upload_id);
+    fs::create_dir_all(CHUNK_STORE)?;
+    // Claim the manifest first, so concurrent manifests cannot both set up the upload
+    let mut manifest = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(format!("{}/manifest", path))?;
+    let prepared = manifest
+        .write_all(chunk_hashes.join("\n").as_bytes())
+        .and_then(|()| known_chunks(chunk_hashes))
+        .and_then(|known| {
+            if mode == StorageMode::Direct {
+                prepare_direct(upload_id, chunk_hashes, &known)?;
+            }
+            Ok(known)
+        });
+    if prepared.is_err() {
+        for file in ["data", "bitmap", "manifest"] {
+            let _ = fs::remove_file(format!("{}/{}", path, file));
+        }
+    }
+    prepared
+}
+
+/// Preallocates the target file of a direct upload, copies the chunks already stored
+/// into place and creates the bitmap that marks the upload as direct
+fn prepare_direct(upload_id: &str, chunk_hashes: &[String], known: &[u64]) -> io::Result<()> {
+    let path = format!("uploads/{}", upload_id);
+    let layout = read_layout(upload_id)?;
+    preallocate(&File::create(format!("{}/data", path))?, layout.total_size)?;
+    fs::write(format!("{}/bitmap", path), vec![0u8; chunk_hashes.len()])?;
+
+    for &chunk_index in known {
+        let hash = &chunk_hashes[chunk_index as usize];
+        let data = fs::read(format!("{}/{}", CHUNK_STORE, hash))?;
+        write_in_place(upload_id, chunk_index, &data)?;
+    }
+    Ok(())
+}
+
+/// Reserves `len` bytes of disk for `file`, so running out of space fails the
+/// upload now rather than a chunk write later, and the file is not left sparse
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let len = libc::off_t::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "File is too large"))?;
+    // Unlike most calls, posix_fallocate returns the error number instead of setting errno
+    // SAFETY: the descriptor belongs to `file`, which stays open for the whole call,
+    // and the range is checked to fit in `off_t` above
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+fn write_layout(upload_id: &str, layout: &Layout) -> io::Result<()> {
+    fs::write(
+        format!("uploads/{}/layout", upload_id),
+        format!("{}\n{}", layout.chunk_size, layout.total_size),
+    )
+}
+
+/// Reads back the name `create_upload_dir` recorded; completions may only store the
+/// upload under this name
+pub fn admitted_name(upload_id: &str) -> io::Result<String> {
+    fs::read_to_string(format!("uploads/{}/name", upload_id))
+}
+
+/// Reads back the layout written by `create_upload_dir`
+pub fn read_layout(upload_id: &str) -> io::Result<Layout> {
+    let contents = fs::read_to_string(format!("uploads/{}/layout", upload_id))?;
+    let mut numbers = contents.lines().map(str::parse::<u64>);
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(chunk_size)), Some(Ok(total_size))) if chunk_size > 0 => Ok(Layout {
+            chunk_size,
+            total_size,
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Corrupt layout for upload {}", upload_id),
+        )),
+    }
+}
+
+/// Upload ids name staging directories, so only accept the UUIDs we hand out
+pub fn is_valid_upload_id(upload_id: &str) -> bool {
+    uuid::Uuid::parse_str(upload_id).is_ok()
+}
+
+/// Returns the indices whose content is already present in the chunk store, marking
+/// those chunks as freshly used so a concurrent sweep keeps them
+fn known_chunks(chunk_hashes: &[String]) -> io::Result<Vec<u64>> {
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut known = Vec::new();
+    for (i, hash) in chunk_hashes.iter().enumerate() {
+        if claim_stored(hash)? {
+            known.push(i as u64);
+        }
+    }
+    Ok(known)
+}
+
+/// Refreshes the modification time of a stored chunk, returning false if it is not
+/// stored. Must be called with `STORE_LOCK` held.
+fn claim_stored(hash: &str) -> io::Result<bool> {
+    match OpenOptions::new()
+        .write(true)
+        .open(format!("{}/{}", CHUNK_STORE, hash))
+    {
+        Ok(file) => file.set_modified(SystemTime::now()).map(|()| true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Looks up the hash the client announced for `chunk_index` in its `ChunkManifest`
+pub fn expected_chunk_hash(upload_id: &str, chunk_index: u64) -> io::Result<Option<String>> {
+    let manifest = read_manifest(upload_id)?;
+    Ok(manifest.get(chunk_index as usize).cloned())
+}
+
+/// Persists a verified chunk according to the mode the upload was created with
+pub fn save_chunk(upload_id: &str, chunk_index: u64, hash: &str, data: &[u8]) -> io::Result<()> {
+    if is_direct(upload_id) {
+        write_in_place(upload_id, chunk_index, data)
+    } else {
+        store_chunk(upload_id, hash, data)?;
+        // A stream has no manifest yet, so record the chunk for the sweep to find
+        if is_stream(upload_id) {
+            OpenOptions::new()
+                .append(true)
+                .open(format!("uploads/{}/stream", upload_id))?
+                .write_all(format!("{}\n", hash).as_bytes())?;
+        }
+        // Nothing lands in the staging directory itself, so mark it active for the janitor
+        OpenOptions::new()
+            .write(true)
+            .open(format!("uploads/{}/layout", upload_id))?
+            .set_modified(SystemTime::now())
+    }
+}
+
+/// Finalizes an upload, verifying the result against the client's whole-file hash and
+/// resolving any name collision according to the configured policy
+pub fn merge_chunks(
+    upload_id: &str,
+    file_name: &str,
+    total_chunks: u64,
+    expected_hash: &str,
+    config: &Config,
+) -> Result<MergedFile, MergeError> {
+    check_file_name(file_name)?;
     let temp_dir = format!("uploads/{}", upload_id);
     let output_path = format!("uploads/{}", file_name);
 
@@ -20,15 +293,469 @@ pub fn merge_chunks(upload_id: &str, file_name: &str, total_chunks: u64) -> io::
         total_chunks, temp_dir, output_path
     );
 
-    let mut output_file = File::create(&output_path)?;
+    let manifest = read_manifest(upload_id)?;
+    if manifest.len() as u64 != total_chunks {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Manifest lists {} chunks but {} were reported",
+                manifest.len(),
+                total_chunks
+            ),
+        )
+        .into());
+    }
 
-    for i in 0..total_chunks {
-        let chunk_path = format!("{}/chunk_{}", temp_dir, i);
-        let mut chunk_file = File::open(&chunk_path)?;
-        std::io::copy(&mut chunk_file, &mut output_file)?;
+    let direct = is_direct(upload_id);
+    let missing = if direct {
+        missing_in_bitmap(upload_id)?
+    } else {
+        missing_in_store(&manifest)
+    };
+    if !missing.is_empty() {
+        return Err(MergeError::MissingChunks(missing));
     }
 
+    // Remember what we were finalizing so an interrupted merge can be replayed on startup
+    fs::write(
+        format!("{}/finalize", temp_dir),
+        format!("{}\n{}", file_name, expected_hash),
+    )?;
+
+    // Readers must never observe a half-written file, so assemble beside it and rename.
+    // Direct uploads were already written in place, so they only need hashing.
+    let (part_path, actual_hash) = if direct {
+        let data_path = PathBuf::from(format!("{}/data", temp_dir));
+        let hash = hash_file(&data_path)?;
+        (data_path, hash)
+    } else {
+        let part_path = part_path(upload_id, &output_path);
+        let hash = assemble_from_store(&manifest, &part_path)?;
+        (part_path, hash)
+    };
+
+    if actual_hash != expected_hash {
+        if !direct {
+            fs::remove_file(&part_path)?;
+        }
+        fs::remove_file(format!("{}/finalize", temp_dir))?;
+        return Err(MergeError::HashMismatch {
+            expected: expected_hash.to_string(),
+            actual: actual_hash,
+        });
+    }
+
+    File::open(&part_path)?.sync_all()?;
+
+    let final_name = match install(&part_path, file_name, config) {
+        Ok(name) => name,
+        Err(e) => {
+            if !direct {
+                fs::remove_file(&part_path)?;
+            }
+            fs::remove_file(format!("{}/finalize", temp_dir))?;
+            return Err(e);
+        }
+    };
+    let output_path = format!("uploads/{}", final_name);
+
+    // Staging is only dropped once the final file is durable
     fs::remove_dir_all(temp_dir)?;
     println!(">> Merge Complete. Saved to {}", output_path);
+    Ok(MergedFile {
+        size: fs::metadata(&output_path)?.len(),
+        file_name: final_name,
+        file_hash: actual_hash,
+    })
+}
+
+/// Moves a complete, synced file into place as `file_name`, resolving any collision
+/// according to the configured policy, and returns the name it was stored under.
+/// On error `part_path` is left for the caller to clean up.
+pub fn install(part_path: &Path, file_name: &str, config: &Config) -> Result<String, MergeError> {
+    let guard = FINALIZE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let final_name = resolve_collision(file_name, config)?;
+    let output_path = format!("uploads/{}", final_name);
+    fs::rename(part_path, &output_path)?;
+    drop(guard);
+    sync_parent_dir(&output_path)?;
+    Ok(final_name)
+}
+
+/// Moves a complete, synced file over `file_name`, which a sync means to update
+/// rather than collide with. The old copy is archived only under the version policy.
+/// On error `part_path` is left for the caller to clean up.
+pub fn replace(part_path: &Path, file_name: &str, config: &Config) -> io::Result<()> {
+    let guard = FINALIZE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if config.collision_policy == CollisionPolicy::Version {
+        versions::archive(file_name, config.max_versions)?;
+    }
+    let output_path = format!("uploads/{}", file_name);
+    fs::rename(part_path, &output_path)?;
+    drop(guard);
+    sync_parent_dir(&output_path)
+}
+
+/// True if a finished file already occupies `file_name`
+pub fn file_exists(file_name: &str) -> bool {
+    Path::new(&format!("uploads/{}", file_name)).is_file()
+}
+
+/// Removes a finished file; archived versions of it are left in place
+pub fn delete_file(file_name: &str) -> io::Result<()> {
+    check_file_name(file_name)?;
+    let _guard = FINALIZE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    fs::remove_file(format!("uploads/{}", file_name))?;
+    println!(">> Deleted {}", file_name);
+    Ok(())
+}
+
+/// Refuses names that would leave `uploads/`, reach the server's own dot-directories
+/// (chunk store, versions, catalog, part files) or shadow a staging directory.
+/// Anything else may contain `/` and is stored in subdirectories.
+pub fn check_file_name(file_name: &str) -> io::Result<()> {
+    let inside = Path::new(file_name).components().all(|c| {
+        matches!(c, std::path::Component::Normal(part) if !part.to_string_lossy().starts_with('.'))
+    });
+    let top_level = file_name.split('/').next().unwrap_or_default();
+    if file_name.is_empty() || file_name.ends_with('/') || !inside || is_valid_upload_id(top_level)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid file name: {}", file_name),
+        ));
+    }
     Ok(())
 }
+
+/// Creates the directories `file_name` is stored in, so part files can be written
+/// beside it
+pub fn create_parent_dirs(file_name: &str) -> io::Result<()> {
+    match Path::new(file_name).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            fs::create_dir_all(Path::new("uploads").join(parent))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Opens a finished file for reading
+pub fn open_stored(file_name: &str) -> io::Result<File> {
+    check_file_name(file_name)?;
+    let file = File::open(format!("uploads/{}", file_name))?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No such file: {}", file_name),
+        ));
+    }
+    Ok(file)
+}
+
+/// Restores an archived version under the same lock that guards finalization
+pub fn restore_version(file_name: &str, version_id: &str, config: &Config) -> io::Result<()> {
+    check_file_name(file_name)?;
+    let _guard = FINALIZE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    versions::restore(file_name, version_id, config.max_versions)
+}
+
+/// Picks the name the upload is stored under, archiving the current file if versioning.
+/// Must be called with `FINALIZE_LOCK` held.
+fn resolve_collision(file_name: &str, config: &Config) -> Result<String, MergeError> {
+    if !file_exists(file_name) {
+        return Ok(file_name.to_string());
+    }
+    match config.collision_policy {
+        CollisionPolicy::Reject => Err(MergeError::NameTaken(file_name.to_string())),
+        CollisionPolicy::Overwrite => Ok(file_name.to_string()),
+        CollisionPolicy::Version => {
+            versions::archive(file_name, config.max_versions)?;
+            Ok(file_name.to_string())
+        }
+        CollisionPolicy::Rename => {
+            let candidate = (1..)
+                .map(|n| renamed(file_name, n))
+                .find(|name| !file_exists(name))
+                .expect("unbounded range always yields a free name");
+            Ok(candidate)
+        }
+    }
+}
+
+/// The `n`th alternative the rename policy tries for `file_name`: "name (n).ext"
+fn renamed(file_name: &str, n: u64) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    path.with_file_name(format!("{} ({}){}", stem, n, extension))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Replays merges that were interrupted by a crash, using the manifest and finalize
+/// marker left in each staging directory. Returns the files that were finalized.
+pub fn recover_interrupted_merges(config: &Config) -> io::Result<Vec<MergedFile>> {
+    let entries = match fs::read_dir("uploads") {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut recovered = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let upload_id = entry.file_name().to_string_lossy().into_owned();
+        let marker = entry.path().join("finalize");
+        if !is_valid_upload_id(&upload_id) || !marker.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&marker)?;
+        let Some((file_name, file_hash)) = contents.split_once('\n') else {
+            eprintln!(">> Ignoring corrupt finalize marker in {}", upload_id);
+            continue;
+        };
+
+        // Without its part file the merge either never got to assemble or already
+        // renamed the result into place; replaying the latter would store it twice
+        let assembled = if is_direct(&upload_id) {
+            entry.path().join("data")
+        } else {
+            part_path(&upload_id, &format!("uploads/{}", file_name))
+        };
+        if !assembled.exists() {
+            if let Some(installed) = installed_copy(file_name, file_hash)? {
+                println!(">> Merge of {} had already finished", installed.file_name);
+                fs::remove_dir_all(entry.path())?;
+                recovered.push(installed);
+                continue;
+            }
+            // A direct upload's data only leaves staging by being renamed into place
+            if is_direct(&upload_id) {
+                fs::remove_dir_all(entry.path())?;
+                continue;
+            }
+        }
+
+        println!(">> Recovering interrupted merge of {}", file_name);
+        let total_chunks = read_manifest(&upload_id)?.len() as u64;
+        match merge_chunks(&upload_id, file_name, total_chunks, file_hash, config) {
+            Ok(merged) => recovered.push(merged),
+            Err(e) => eprintln!(">> Recovery of {} failed: {}", upload_id, e),
+        }
+    }
+    Ok(recovered)
+}
+
+/// Finds a finished file with the given hash under `file_name` or one of the names
+/// the rename policy would have picked for it
+fn installed_copy(file_name: &str, file_hash: &str) -> io::Result<Option<MergedFile>> {
+    let candidates = std::iter::once(file_name.to_string())
+        .chain((1..).map(|n| renamed(file_name, n)))
+        .take_while(|name| file_exists(name));
+    for name in candidates {
+        let path = format!("uploads/{}", name);
+        if hash_file(Path::new(&path))? == file_hash {
+            return Ok(Some(MergedFile {
+                size: fs::metadata(&path)?.len(),
+                file_name: name,
+                file_hash: file_hash.to_string(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Deletes stored chunks that no staging directory refers to and that have not been
+/// used for `grace`, and returns how many were deleted and their total size. Chunks
+/// of finished uploads are kept for `grace` so re-uploads can still skip them.
+pub fn sweep_chunk_store(grace: Duration) -> io::Result<(usize, u64)> {
+    let entries = match fs::read_dir(CHUNK_STORE) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+    // Chunks claimed after this is taken are fresh, so the sweep skips them anyway
+    let referenced = referenced_chunks()?;
+
+    let (mut swept, mut bytes) = (0, 0);
+    for entry in entries {
+        let entry = entry?;
+        let hash = entry.file_name().to_string_lossy().into_owned();
+        if !is_valid_chunk_hash(&hash) || referenced.contains(&hash) {
+            continue;
+        }
+        let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let unused_for = SystemTime::now()
+            .duration_since(metadata.modified()?)
+            .unwrap_or_default();
+        if unused_for >= grace {
+            fs::remove_file(entry.path())?;
+            swept += 1;
+            bytes += metadata.len();
+        }
+    }
+    Ok((swept, bytes))
+}
+
+/// Collects the chunk hashes listed by every staging directory: the manifests of
+/// announced uploads and the chunks streams have received so far
+fn referenced_chunks() -> io::Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir("uploads")? {
+        let entry = entry?;
+        if !is_valid_upload_id(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        for list in ["manifest", "stream"] {
+            match fs::read_to_string(entry.path().join(list)) {
+                Ok(contents) => referenced.extend(contents.lines().map(str::to_string)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(referenced)
+}
+
+/// Stores a chunk under its hash; identical content is only ever written once
+fn store_chunk(upload_id: &str, hash: &str, data: &[u8]) -> io::Result<()> {
+    let path = format!("{}/{}", CHUNK_STORE, hash);
+    let stored = {
+        let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        claim_stored(hash)?
+    };
+    if stored {
+        return Ok(());
+    }
+    // Concurrent uploads may race on the same content, so publish with a rename
+    let temp_path = format!("{}.{}.tmp", path, upload_id);
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(data)?;
+    temp_file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+/// Writes a chunk at its offset in the preallocated file, then flags it in the bitmap.
+/// The bitmap holds one byte per chunk so concurrent workers never share a write.
+fn write_in_place(upload_id: &str, chunk_index: u64, data: &[u8]) -> io::Result<()> {
+    let layout = read_layout(upload_id)?;
+    let data_file = OpenOptions::new()
+        .write(true)
+        .open(format!("uploads/{}/data", upload_id))?;
+    data_file.write_all_at(data, chunk_index * layout.chunk_size)?;
+
+    let bitmap = OpenOptions::new()
+        .write(true)
+        .open(format!("uploads/{}/bitmap", upload_id))?;
+    bitmap.write_all_at(&[1], chunk_index)
+}
+
+fn is_direct(upload_id: &str) -> bool {
+    Path::new(&format!("uploads/{}/bitmap", upload_id)).exists()
+}
+
+fn missing_in_store(manifest: &[String]) -> Vec<u64> {
+    manifest
+        .iter()
+        .enumerate()
+        .filter(|(_, hash)| !Path::new(&format!("{}/{}", CHUNK_STORE, hash)).exists())
+        .map(|(i, _)| i as u64)
+        .collect()
+}
+
+fn missing_in_bitmap(upload_id: &str) -> io::Result<Vec<u64>> {
+    let bitmap = fs::read(format!("uploads/{}/bitmap", upload_id))?;
+    Ok(bitmap
+        .iter()
+        .enumerate()
+        .filter(|(_, done)| **done == 0)
+        .map(|(i, _)| i as u64)
+        .collect())
+}
+
+/// Concatenates the manifest's chunks into `part_path`, returning the hash of the result
+fn assemble_from_store(manifest: &[String], part_path: &Path) -> io::Result<String> {
+    let mut part_file = File::create(part_path)?;
+    let mut hasher = Sha256::new();
+
+    for hash in manifest {
+        let chunk = fs::read(format!("{}/{}", CHUNK_STORE, hash))?;
+        hasher.update(&chunk);
+        part_file.write_all(&chunk)?;
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hashes a stored file in one pass: the whole file, and every `chunk_size` slice
+/// of it when asked. Returns the size alongside the hashes.
+pub fn hash_stored(
+    file_name: &str,
+    chunk_size: Option<u64>,
+) -> io::Result<(u64, String, Vec<String>)> {
+    let mut file = open_stored(file_name)?;
+    let mut file_hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut size = 0;
+    let slice = chunk_size.unwrap_or(1024 * 1024);
+    loop {
+        let mut buffer = Vec::with_capacity(slice as usize);
+        (&mut file).take(slice).read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            break;
+        }
+        file_hasher.update(&buffer);
+        if chunk_size.is_some() {
+            chunk_hashes.push(hash_chunk(&buffer));
+        }
+        size += buffer.len() as u64;
+    }
+    Ok((size, hex::encode(file_hasher.finalize()), chunk_hashes))
+}
+
+/// Temporary name in the same directory as the target, so the final rename is atomic
+pub fn part_path(upload_id: &str, output_path: &str) -> PathBuf {
+    let output_path = Path::new(output_path);
+    let base_name = output_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    output_path.with_file_name(format!(".{}.{}.part", base_name, upload_id))
+}
+
+/// Persists the directory entry created by a rename
+fn sync_parent_dir(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+fn read_manifest(upload_id: &str) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(format!("uploads/{}/manifest", upload_id))?;
+    Ok(contents.lines().map(str::to_string).collect())
+}
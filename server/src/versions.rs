@@ -0,0 +1,102 @@
+use shared::VersionInfo;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Old versions live under `uploads/.versions/<file_name>/<version_id>`
+const VERSIONS_DIR: &str = "uploads/.versions";
+
+/// Version ids are timestamps, so anything else would be a path injection attempt
+pub fn is_valid_version_id(version_id: &str) -> bool {
+    !version_id.is_empty() && version_id.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Moves the current `uploads/<file_name>` aside as a new version and prunes the
+/// oldest ones beyond `max_versions`. Does nothing if the file does not exist.
+pub fn archive(file_name: &str, max_versions: usize) -> io::Result<()> {
+    let current = format!("uploads/{}", file_name);
+    if !Path::new(&current).is_file() {
+        return Ok(());
+    }
+
+    let dir = format!("{}/{}", VERSIONS_DIR, file_name);
+    fs::create_dir_all(&dir)?;
+    let mut version_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    while Path::new(&format!("{}/{}", dir, version_id)).exists() {
+        version_id += 1;
+    }
+    fs::rename(&current, format!("{}/{}", dir, version_id))?;
+    println!(">> Archived {} as version {}", file_name, version_id);
+
+    let versions = list(file_name)?;
+    for old in versions.iter().skip(max_versions) {
+        fs::remove_file(format!("{}/{}", dir, old.version_id))?;
+    }
+    Ok(())
+}
+
+/// Lists retained versions of a file, newest first
+pub fn list(file_name: &str) -> io::Result<Vec<VersionInfo>> {
+    let entries = match fs::read_dir(format!("{}/{}", VERSIONS_DIR, file_name)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut versions = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let version_id = entry.file_name().to_string_lossy().into_owned();
+        if !is_valid_version_id(&version_id) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let modified_at = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        versions.push(VersionInfo {
+            version_id,
+            size: metadata.len(),
+            modified_at,
+        });
+    }
+    // Ids are nanosecond timestamps, so numeric order is age order
+    versions.sort_by_key(|v| std::cmp::Reverse(v.version_id.parse::<u128>().unwrap_or(0)));
+    Ok(versions)
+}
+
+/// Puts a retained version back in place; the file it replaces becomes a version itself
+pub fn restore(file_name: &str, version_id: &str, max_versions: usize) -> io::Result<()> {
+    let version_path = format!("{}/{}/{}", VERSIONS_DIR, file_name, version_id);
+    if !Path::new(&version_path).is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No version {} of {}", version_id, file_name),
+        ));
+    }
+
+    // Move the chosen version out first so pruning during archive cannot delete it.
+    // It waits beside the file, so the final rename stays within one directory.
+    let current = format!("uploads/{}", file_name);
+    let current_path = Path::new(&current);
+    let base_name = current_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let staged = current_path.with_file_name(format!(".{}.{}.restore", base_name, version_id));
+    // The file's directory may have been removed since the version was archived
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&version_path, &staged)?;
+    archive(file_name, max_versions)?;
+    fs::rename(&staged, &current)?;
+    println!(">> Restored {} to version {}", file_name, version_id);
+    Ok(())
+}
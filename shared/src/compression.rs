@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Algorithm applied to a chunk before encryption
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+/// Compression must shrink a chunk by at least this fraction to be worth sending
+const MIN_SAVINGS: f64 = 0.05;
+
+/// Compresses `data`, falling back to `Compression::None` when it barely shrinks
+/// (media, archives and other already-compressed content). Uncompressed chunks are
+/// borrowed rather than copied.
+pub fn compress_chunk(data: &[u8], algorithm: Compression) -> (Compression, Cow<'_, [u8]>) {
+    let compressed = match algorithm {
+        Compression::None => None,
+        Compression::Zstd => zstd::bulk::compress(data, 3).ok(),
+        Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+    };
+    match compressed {
+        Some(c) if (c.len() as f64) < data.len() as f64 * (1.0 - MIN_SAVINGS) => {
+            (algorithm, Cow::Owned(c))
+        }
+        _ => (Compression::None, Cow::Borrowed(data)),
+    }
+}
+
+/// Reverses `compress_chunk`, refusing output larger than `max_size` so a
+/// malicious peer cannot make us allocate unbounded memory
+pub fn decompress_chunk(
+    data: &[u8],
+    algorithm: Compression,
+    max_size: usize,
+) -> Result<Vec<u8>, String> {
+    match algorithm {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::bulk::decompress(data, max_size)
+            .map_err(|e| format!("Zstd decompression failed: {}", e)),
+        Compression::Lz4 => {
+            if data.len() < 4 {
+                return Err("Data too short".to_string());
+            }
+            let (size_bytes, body) = data.split_at(4);
+            let size = u32::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+            if size > max_size {
+                return Err(format!("Decompressed size {} exceeds limit", size));
+            }
+            lz4_flex::decompress(body, size).map_err(|e| format!("Lz4 decompression failed: {}", e))
+        }
+    }
+}
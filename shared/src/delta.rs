@@ -0,0 +1,304 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
+
+/// Smallest block compared by a delta sync
+const MIN_BLOCK_SIZE: u64 = 2 * 1024;
+
+/// Largest block compared by a delta sync
+const MAX_BLOCK_SIZE: u64 = 128 * 1024;
+
+/// Literal bytes are sent in frames of at most this size
+pub const MAX_LITERAL_FRAME: usize = 1024 * 1024;
+
+/// Checksums of one block of the file already on the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockSignature {
+    /// Rolling checksum, cheap to slide one byte at a time
+    pub weak: u32,
+    /// Truncated SHA-256, checked only when the weak checksum matches
+    pub strong: String,
+}
+
+/// One step in rebuilding the new file from the old one
+pub enum Delta<'a> {
+    /// Reuse `len` bytes of the old file starting at `offset`
+    Copy { offset: u64, len: u64 },
+    /// Bytes the old file does not contain
+    Literal(&'a [u8]),
+}
+
+/// Block size for a file of `file_size` bytes: about its square root, like rsync,
+/// so the signature and the number of blocks grow together
+pub fn block_size_for(file_size: u64) -> u64 {
+    (file_size.isqrt().div_ceil(1024) * 1024).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// The rsync weak checksum: `a` is the byte sum, `b` weights each byte by its
+/// distance from the end of the window. Both can be updated in O(1) per byte.
+#[derive(Default)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut rolling = Rolling::default();
+        for &byte in data {
+            rolling.push(byte);
+        }
+        rolling
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.a = self.a.wrapping_add(byte as u32);
+        self.b = self.b.wrapping_add(self.a);
+        self.len += 1;
+    }
+
+    fn pop(&mut self, byte: u8) {
+        self.a = self.a.wrapping_sub(byte as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(byte as u32));
+        self.len -= 1;
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data)[..8])
+}
+
+/// Splits `reader` into blocks of `block_size` and checksums each one
+pub fn signature(mut reader: impl Read, block_size: u64) -> io::Result<Vec<BlockSignature>> {
+    let mut blocks = Vec::new();
+    let mut buffer = Vec::with_capacity(block_size as usize);
+    loop {
+        buffer.clear();
+        (&mut reader).take(block_size).read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            return Ok(blocks);
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&buffer).digest(),
+            strong: strong_hash(&buffer),
+        });
+    }
+}
+
+/// Slides a window over `reader` looking for blocks of the old file described by
+/// `blocks`, and calls `emit` with copy instructions for every match and literal
+/// data for everything in between. Adjacent copies are merged.
+pub fn compute_delta<E: From<io::Error>>(
+    reader: impl Read,
+    block_size: u64,
+    blocks: &[BlockSignature],
+    mut emit: impl FnMut(Delta<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in blocks.iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(i);
+    }
+
+    let block_size = block_size as usize;
+    let mut bytes = io::BufReader::new(reader).bytes();
+    let mut window: VecDeque<u8> = VecDeque::with_capacity(block_size);
+    let mut literal = Vec::new();
+    let mut pending_copy: Option<(u64, u64)> = None;
+    let mut eof = false;
+
+    let mut fill = |window: &mut VecDeque<u8>, eof: &mut bool| -> io::Result<()> {
+        while window.len() < block_size && !*eof {
+            match bytes.next().transpose()? {
+                Some(byte) => window.push_back(byte),
+                None => *eof = true,
+            }
+        }
+        Ok(())
+    };
+
+    fill(&mut window, &mut eof)?;
+    let mut rolling = Rolling::new(window.make_contiguous());
+
+    while !window.is_empty() {
+        // Only a full window, or the file's tail, can line up with an old block
+        let matched = if window.len() == block_size || eof {
+            by_weak.get(&rolling.digest()).and_then(|candidates| {
+                let strong = strong_hash(window.make_contiguous());
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|&i| blocks[i].strong == strong)
+            })
+        } else {
+            None
+        };
+
+        if let Some(index) = matched {
+            if !literal.is_empty() {
+                flush_copy(&mut pending_copy, &mut emit)?;
+                emit(Delta::Literal(&literal))?;
+                literal.clear();
+            }
+            let offset = index as u64 * block_size as u64;
+            let len = window.len() as u64;
+            pending_copy = match pending_copy {
+                Some((start, run)) if start + run == offset => Some((start, run + len)),
+                _ => {
+                    flush_copy(&mut pending_copy, &mut emit)?;
+                    Some((offset, len))
+                }
+            };
+            window.clear();
+            fill(&mut window, &mut eof)?;
+            rolling = Rolling::new(window.make_contiguous());
+            continue;
+        }
+
+        let out = window.pop_front().expect("window is not empty");
+        rolling.pop(out);
+        literal.push(out);
+        fill(&mut window, &mut eof)?;
+        if window.len() as u32 > rolling.len {
+            rolling.push(*window.back().expect("window was just filled"));
+        }
+        if literal.len() >= MAX_LITERAL_FRAME {
+            flush_copy(&mut pending_copy, &mut emit)?;
+            emit(Delta::Literal(&literal))?;
+            literal.clear();
+        }
+    }
+
+    flush_copy(&mut pending_copy, &mut emit)?;
+    if !literal.is_empty() {
+        emit(Delta::Literal(&literal))?;
+    }
+    Ok(())
+}
+
+fn flush_copy<E>(
+    pending: &mut Option<(u64, u64)>,
+    emit: &mut impl FnMut(Delta<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    match pending.take() {
+        Some((offset, len)) => emit(Delta::Copy { offset, len }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic filler that does not repeat within a block
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Diffs `new` against `old` and rebuilds it the way the server does. Returns the
+    /// rebuilt file and how many bytes had to be sent as literals.
+    fn round_trip(old: &[u8], new: &[u8]) -> (Vec<u8>, usize) {
+        let block_size = block_size_for(old.len() as u64);
+        let blocks = signature(old, block_size).unwrap();
+        let mut rebuilt = Vec::new();
+        let mut literal_bytes = 0;
+        compute_delta::<io::Error>(new, block_size, &blocks, |delta| {
+            match delta {
+                Delta::Copy { offset, len } => {
+                    rebuilt.extend_from_slice(&old[offset as usize..(offset + len) as usize])
+                }
+                Delta::Literal(data) => {
+                    assert!(data.len() <= MAX_LITERAL_FRAME);
+                    literal_bytes += data.len();
+                    rebuilt.extend_from_slice(data);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        (rebuilt, literal_bytes)
+    }
+
+    #[test]
+    fn unchanged_file_is_copied_whole() {
+        let old = noise(100_000, 1);
+        let (rebuilt, literal_bytes) = round_trip(&old, &old);
+        assert_eq!(rebuilt, old);
+        assert_eq!(literal_bytes, 0);
+    }
+
+    #[test]
+    fn insert_sends_little_more_than_the_new_bytes() {
+        let old = noise(100_000, 2);
+        let inserted = noise(1_000, 3);
+        let mut new = old[..40_000].to_vec();
+        new.extend_from_slice(&inserted);
+        new.extend_from_slice(&old[40_000..]);
+
+        let (rebuilt, literal_bytes) = round_trip(&old, &new);
+        assert_eq!(rebuilt, new);
+        let block_size = block_size_for(old.len() as u64) as usize;
+        assert!(literal_bytes <= inserted.len() + block_size);
+    }
+
+    #[test]
+    fn delete_sends_at_most_one_block() {
+        let old = noise(100_000, 4);
+        let mut new = old[..30_000].to_vec();
+        new.extend_from_slice(&old[45_000..]);
+
+        let (rebuilt, literal_bytes) = round_trip(&old, &new);
+        assert_eq!(rebuilt, new);
+        assert!(literal_bytes <= block_size_for(old.len() as u64) as usize);
+    }
+
+    #[test]
+    fn short_tail_block_is_matched() {
+        let old = noise(10_000 + 123, 5);
+        let block_size = block_size_for(old.len() as u64) as usize;
+        assert_ne!(old.len() % block_size, 0);
+
+        let (rebuilt, literal_bytes) = round_trip(&old, &old);
+        assert_eq!(rebuilt, old);
+        assert_eq!(literal_bytes, 0);
+    }
+
+    #[test]
+    fn appended_tail_is_rebuilt() {
+        let old = noise(10_000 + 123, 6);
+        let mut new = old.clone();
+        new.extend_from_slice(&noise(77, 7));
+
+        let (rebuilt, literal_bytes) = round_trip(&old, &new);
+        assert_eq!(rebuilt, new);
+        assert!(literal_bytes <= block_size_for(old.len() as u64) as usize + 77);
+    }
+
+    #[test]
+    fn new_file_is_sent_in_bounded_frames() {
+        let new = noise(MAX_LITERAL_FRAME * 2 + 500, 8);
+        let (rebuilt, literal_bytes) = round_trip(&[], &new);
+        assert_eq!(rebuilt, new);
+        assert_eq!(literal_bytes, new.len());
+    }
+
+    #[test]
+    fn empty_file_needs_no_instructions() {
+        let (rebuilt, literal_bytes) = round_trip(&noise(5_000, 9), &[]);
+        assert!(rebuilt.is_empty());
+        assert_eq!(literal_bytes, 0);
+    }
+}
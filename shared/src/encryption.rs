@@ -1,30 +1,47 @@
 use aes_gcm::{
     AeadCore, Aes256Gcm, KeyInit, Nonce,
-    aead::{Aead, OsRng},
+    aead::{Aead, AeadInPlace, OsRng},
 };
+
+/// Length of the random nonce every encrypted chunk starts with
+const NONCE_LEN: usize = 12;
+
+/// Bytes added to every chunk by encryption: a 12-byte nonce and a 16-byte tag
+pub const OVERHEAD: usize = NONCE_LEN + 16;
+
 pub fn encrypt_chunk(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(data.len() + OVERHEAD);
+    encrypt_chunk_into(data, key, &mut result)?;
+    Ok(result)
+}
+
+/// Like `encrypt_chunk`, but replaces the contents of `out`, so a caller that keeps
+/// its buffers does not allocate per chunk
+pub fn encrypt_chunk_into(data: &[u8], key: &[u8; 32], out: &mut Vec<u8>) -> Result<(), String> {
     let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid key length".to_string())?;
 
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
 
-    let ciphertext = cipher
-        .encrypt(&nonce, data)
+    out.clear();
+    out.reserve(data.len() + OVERHEAD);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(data);
+    let tag = cipher
+        .encrypt_in_place_detached(&nonce, b"", &mut out[NONCE_LEN..])
         .map_err(|e| format!("Encryption failed: {:?}", e))?;
+    out.extend_from_slice(&tag);
 
-    let mut result = nonce.to_vec();
-    result.extend_from_slice(&ciphertext);
-
-    Ok(result)
+    Ok(())
 }
 
 pub fn decrypt_chunk(encrypted_data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
-    if encrypted_data.len() < 12 {
+    if encrypted_data.len() < NONCE_LEN {
         return Err("Data too short".to_string());
     }
 
     let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid key length".to_string())?;
 
-    let (nonce_bytes, ciphertext) = encrypted_data.split_at(12);
+    let (nonce_bytes, ciphertext) = encrypted_data.split_at(NONCE_LEN);
     let nonce = Nonce::from_slice(nonce_bytes);
 
     let plaintext = cipher
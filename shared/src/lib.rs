@@ -1,6 +1,10 @@
+pub mod compression;
+pub mod delta;
 pub mod encryption;
-use hex;
+use compression::Compression;
+use delta::BlockSignature;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use thiserror::Error;
@@ -24,6 +28,19 @@ pub enum ParaFlowError {
 
     #[error("Encryption/Decryption Error: {0}")]
     EncryptionError(String),
+
+    #[error("Server Error: {0}")]
+    ServerError(String),
+
+    #[error("Transfer Failed: {0}")]
+    TransferFailed(String),
+
+    #[error("Transfer Cancelled")]
+    Cancelled,
+
+    /// The server had no free connection slot; try again after this many milliseconds
+    #[error("Server Busy: try again in {0} ms")]
+    ServerBusy(u64),
 }
 
 // Global Shared Key - To be moved to environment variables in Phase 2
@@ -36,6 +53,8 @@ pub const ENCRYPTION_KEY: [u8; 32] = [
 pub enum Message {
     LoginRequest {
         client_id: String,
+        /// Compression algorithms the client can use, most preferred first
+        compression: Vec<Compression>,
     },
     LoginChallenge {
         salt: String,
@@ -45,20 +64,38 @@ pub enum Message {
     },
     Welcome {
         session_id: String,
+        /// Algorithm chosen by the server; chunks may use it or be sent uncompressed
+        compression: Compression,
     },
     InitUpload {
         file_name: String,
         total_size: u64,
     },
+    /// Like `InitUpload` for input of unknown length, such as a pipe; the size and
+    /// chunk hashes are sent with `CompleteStream` instead
+    InitStream {
+        file_name: String,
+    },
     InitAck {
+        /// Size of every chunk except possibly the last; the client must slice by it
         chunk_size: u64,
         upload_id: String,
     },
+    ChunkManifest {
+        upload_id: String,
+        /// SHA-256 of every plaintext chunk, in order, used for deduplication
+        chunk_hashes: Vec<String>,
+    },
+    ManifestAck {
+        /// Chunk indices the server already holds and which must not be sent
+        known_chunks: Vec<u64>,
+    },
     ChunkMeta {
         upload_id: String,
         chunk_index: u64,
         size: usize,
         hash: String,
+        compression: Compression,
     },
     ChunkAck {
         chunk_index: u64,
@@ -70,12 +107,158 @@ pub enum Message {
         upload_id: String,
         file_name: String,
         total_chunks: u64,
+        /// SHA-256 of the whole source file, checked by the server after merging
+        file_hash: String,
+    },
+    /// Completes an upload started with `InitStream`
+    CompleteStream {
+        upload_id: String,
+        file_name: String,
+        total_size: u64,
+        /// SHA-256 of every plaintext chunk, in order
+        chunk_hashes: Vec<String>,
+        /// SHA-256 of the whole stream, checked by the server after merging
+        file_hash: String,
+    },
+    CompleteAck {
+        /// Name the file was stored under, which differs from the request when renamed
+        file_name: String,
+        file_hash: String,
+    },
+    CompleteFailed {
+        /// Chunk indices the server never received; empty when the merge itself failed
+        missing_chunks: Vec<u64>,
+        reason: String,
+    },
+    ListVersions {
+        file_name: String,
+    },
+    VersionList {
+        /// Newest first
+        versions: Vec<VersionInfo>,
+    },
+    RestoreVersion {
+        file_name: String,
+        version_id: String,
+    },
+    RestoreAck {
+        file_name: String,
+        version_id: String,
+    },
+    ListFiles,
+    FileList {
+        files: Vec<FileInfo>,
+    },
+    StatFile {
+        file_name: String,
+    },
+    FileStat {
+        info: FileInfo,
+    },
+    DeleteFile {
+        file_name: String,
+    },
+    DeleteAck {
+        file_name: String,
+    },
+    /// Starts a delta sync of `file_name`; the server answers with a `Signature`
+    /// of its current copy (empty if it has none)
+    SyncRequest {
+        file_name: String,
+    },
+    Signature {
+        block_size: u64,
+        blocks: Vec<BlockSignature>,
+    },
+    /// Appends `len` bytes of the old file, starting at `offset`, to the new one
+    DeltaCopy {
+        offset: u64,
+        len: u64,
+    },
+    /// Appends literal bytes; an encrypted body of `size` bytes follows, like `ChunkMeta`
+    DeltaData {
+        size: usize,
+        hash: String,
+        compression: Compression,
+    },
+    /// Ends a delta sync; answered with `CompleteAck` or `CompleteFailed`
+    DeltaComplete {
+        /// SHA-256 of the new file, checked by the server after rebuilding it
+        file_hash: String,
+    },
+    /// Asks for a stored file; the server answers with `DownloadInfo`
+    DownloadRequest {
+        file_name: String,
+    },
+    DownloadInfo {
+        file_name: String,
+        size: u64,
+        /// SHA-256 of the whole file, checked by the client once every chunk is in
+        file_hash: String,
+        /// Size of every chunk except possibly the last; `FetchChunk` must use it
+        chunk_size: u64,
+    },
+    /// Asks for one chunk of a stored file; answered with `ChunkData`
+    FetchChunk {
+        file_name: String,
+        chunk_index: u64,
+        chunk_size: u64,
+    },
+    /// One chunk of a download; an encrypted body of `size` bytes follows, like `ChunkMeta`
+    ChunkData {
+        chunk_index: u64,
+        size: usize,
+        hash: String,
+        compression: Compression,
+    },
+    /// Asks the server to hash a stored file as it is on disk now; answered with
+    /// `HashReport`
+    HashRequest {
+        file_name: String,
+        /// Also hash every chunk of this size, to locate differences
+        chunk_size: Option<u64>,
+    },
+    HashReport {
+        file_name: String,
+        size: u64,
+        /// SHA-256 of the whole file
+        file_hash: String,
+        /// SHA-256 of every plaintext chunk, in order; empty unless requested
+        chunk_hashes: Vec<String>,
+    },
+    /// Sent in place of a reply when the server is shutting down; it closes the
+    /// connection afterwards, and the request should be retried once it is back
+    ShuttingDown,
+    /// Sent in place of `LoginChallenge` when every connection slot is taken; the
+    /// server closes the connection, and the client should wait before trying again
+    Busy {
+        retry_after_ms: u64,
     },
     ErrorMessage {
         text: String,
     },
 }
 
+/// Catalog entry describing a stored file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileInfo {
+    pub file_name: String,
+    pub size: u64,
+    pub file_hash: String,
+    pub uploaded_by: String,
+    /// Unix timestamp (seconds)
+    pub uploaded_at: u64,
+}
+
+/// An archived copy of a file, kept when a newer upload replaced it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionInfo {
+    pub version_id: String,
+    pub size: u64,
+    /// Unix timestamp (seconds) of the version's last modification
+    pub modified_at: u64,
+}
+
 /// Centralized helper to send messages over a TCP stream
 pub fn send_message(stream: &mut TcpStream, msg: &Message) -> Result<(), ParaFlowError> {
     let json = serde_json::to_string(msg)?;
@@ -117,3 +300,10 @@ pub fn load_encryption_key() -> Result<[u8; 32], ParaFlowError> {
     key.copy_from_slice(&bytes);
     Ok(key)
 }
+
+/// Hex-encoded SHA-256 digest, used both for transport checks and chunk addressing
+pub fn hash_chunk(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}